@@ -2,14 +2,19 @@
 extern crate glium;
 extern crate inline_tweak;
 extern crate rand;
+extern crate fluid_gdc03;
 
-mod fluid;
-mod types;
 use glium::{backend::glutin_backend::GlutinFacade};
 use inline_tweak::tweak;
 use rand::Rng;
 use std::time::Instant;
-use types::*;
+use fluid_gdc03::*;
+
+const X_SIZE: usize = 96;
+const Y_SIZE: usize = 96;
+const Z_SIZE: usize = 4;
+/// Simulated time per rendered frame, in seconds.
+const FRAME_TIME: f32 = 0.01;
 
 #[derive(Copy, Clone)]
 struct Vertex {
@@ -96,12 +101,18 @@ fn main() {
 
     // Create texture data buffer for fluid
 
-    let mut wind_grid = WindGrid {
-        x_vel: vec![0_f32; SIZE_1D].into_boxed_slice(),
-        y_vel: vec![0_f32; SIZE_1D].into_boxed_slice(),
-        z_vel: vec![0_f32; SIZE_1D].into_boxed_slice(),
-        density: vec![0_f32; SIZE_1D].into_boxed_slice(),
+    let config = SolverConfig {
+        dt: 0.01,
+        diffusion: 0.1,
+        viscosity: 0.1,
+        max_cfl: 5.0,
+        max_substeps: 4,
+        ..SolverConfig::default()
     };
+    let mut grid = WindGrid::new(X_SIZE, Y_SIZE, Z_SIZE);
+    // One meter across, like the unit square of the paper
+    grid.transform.cell_size = 1.0 / X_SIZE as f32;
+    let mut solver = FluidSolver::new(grid, config).unwrap();
 
     let mut last_t = Instant::now();
 
@@ -121,6 +132,11 @@ fn main() {
             println!("{} (total avg: {})", fps, avg_fps);
         }
         last_t = new_now;
+
+        // Sources are rates applied on every step, so only keep this frame's
+        solver.grid_mut().clear_sources();
+        let sim_dt = FRAME_TIME;
+
         // listing the events produced by the window and waiting to be received
 
         for ev in display.poll_events() {
@@ -133,25 +149,25 @@ fn main() {
                 glium::glutin::Event::MouseInput(
                     glium::glutin::ElementState::Pressed,
                     glium::glutin::MouseButton::Right,
-                ) => wind_grid.add_density_source(
+                ) => solver.grid_mut().add_density_source(
                     Pos {
                         x: mouse_x as usize,
                         y: mouse_y as usize,
                         z: 2,
                     },
-                    -30.0,
+                    -30.0 / sim_dt,
                 ),
 
                 glium::glutin::Event::MouseInput(
                     glium::glutin::ElementState::Pressed,
                     glium::glutin::MouseButton::Left,
-                ) => wind_grid.add_density_source(
+                ) => solver.grid_mut().add_density_source(
                     Pos {
                         x: mouse_x as usize,
                         y: mouse_y as usize,
                         z: 2,
                     },
-                    30.0,
+                    30.0 / sim_dt,
                 ),
                 _ => (),
             }
@@ -166,7 +182,7 @@ fn main() {
         let x = rng.gen_range(1.0..X_SIZE as f32);
 
         for i in 1..x as usize {
-            wind_grid.add_velocity_source(
+            solver.grid_mut().add_velocity_source(
                 Pos { x: tweak!(i), y: tweak!(50), z: tweak!(4) },
                 Vel {
                     x: tweak!(312.0) * r.sin(),
@@ -177,15 +193,8 @@ fn main() {
         }
 
         // Process fluids
-        fluid::step_fluid(
-            &mut wind_grid.density,
-            &mut wind_grid.x_vel,
-            &mut wind_grid.y_vel,
-            &mut wind_grid.z_vel,
-            0.01,
-            0.1,
-            true,
-        );
+        solver.advance(FRAME_TIME);
+        let wind_grid = solver.grid();
 
         // Re buffer texture
         use std::borrow::Cow;
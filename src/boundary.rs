@@ -0,0 +1,333 @@
+use obstacle::Obstacles;
+use types::*;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Condition applied at one outer face of the grid.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Boundary {
+    /// Solid wall the fluid sticks to: all velocity components vanish at
+    /// the face.
+    NoSlip,
+    /// Solid wall the fluid slides along: only the normal velocity vanishes.
+    FreeSlip,
+    /// Open face: every field has zero gradient across it, so fluid and
+    /// smoke leave freely.
+    Outflow,
+    /// Wraps around to the opposite face, which must be periodic too.
+    Periodic,
+    /// Fluid enters with this world velocity in m/s. Scalars have zero
+    /// gradient.
+    Inflow(Vel),
+}
+
+/// Boundary condition for each of the six outer faces.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct BoundaryConditions {
+    pub x_min: Boundary,
+    pub x_max: Boundary,
+    pub y_min: Boundary,
+    pub y_max: Boundary,
+    pub z_min: Boundary,
+    pub z_max: Boundary,
+}
+
+impl BoundaryConditions {
+    /// The same condition on every face.
+    pub fn uniform(boundary: Boundary) -> BoundaryConditions {
+        BoundaryConditions {
+            x_min: boundary,
+            x_max: boundary,
+            y_min: boundary,
+            y_max: boundary,
+            z_min: boundary,
+            z_max: boundary,
+        }
+    }
+
+    /// Returns the first face whose condition is inconsistent, with the
+    /// reason why.
+    pub(crate) fn invalid_face(&self) -> Option<(&'static str, &'static str)> {
+        let pairs = [
+            ("boundary.x_min", self.x_min, "boundary.x_max", self.x_max),
+            ("boundary.y_min", self.y_min, "boundary.y_max", self.y_max),
+            ("boundary.z_min", self.z_min, "boundary.z_max", self.z_max),
+        ];
+        for &(min_name, min, max_name, max) in pairs.iter() {
+            if (min == Boundary::Periodic) != (max == Boundary::Periodic) {
+                let name = if min == Boundary::Periodic { min_name } else { max_name };
+                return Some((name, "is periodic but the opposite face is not"));
+            }
+            for &(name, face) in [(min_name, min), (max_name, max)].iter() {
+                if let Boundary::Inflow(v) = face {
+                    if !(v.x.is_finite() && v.y.is_finite() && v.z.is_finite()) {
+                        return Some((name, "must have a finite inflow velocity"));
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+impl Default for BoundaryConditions {
+    /// Reflecting walls on all sides.
+    fn default() -> BoundaryConditions {
+        BoundaryConditions::uniform(Boundary::FreeSlip)
+    }
+}
+
+/// What kind of quantity a grid holds, which decides how it is mirrored into
+/// the ghost cells.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Field {
+    Scalar,
+    VelX,
+    VelY,
+    VelZ,
+    /// Velocity components stored on the cell faces of a staggered grid.
+    FaceX,
+    FaceY,
+    FaceZ,
+}
+
+impl Field {
+    /// Velocity component held by the field, if any.
+    pub fn component(self) -> Option<usize> {
+        match self {
+            Field::Scalar => None,
+            Field::VelX | Field::FaceX => Some(0),
+            Field::VelY | Field::FaceY => Some(1),
+            Field::VelZ | Field::FaceZ => Some(2),
+        }
+    }
+
+    fn face_axis(self) -> Option<usize> {
+        match self {
+            Field::FaceX => Some(0),
+            Field::FaceY => Some(1),
+            Field::FaceZ => Some(2),
+            _ => None,
+        }
+    }
+}
+
+/// Ghost cell value across a face perpendicular to `axis` (0 = x, 1 = y,
+/// 2 = z). `inset` is the interior cell next to the face, `wrapped` the
+/// interior cell next to the opposite face.
+fn ghost_value(boundary: Boundary, field: Field, axis: usize, inset: f32, wrapped: f32) -> f32 {
+    match (boundary, field.component()) {
+        (Boundary::Periodic, _) => wrapped,
+        (_, None) | (Boundary::Outflow, _) => inset,
+        (Boundary::NoSlip, Some(_)) => -inset,
+        (Boundary::FreeSlip, Some(c)) => {
+            if c == axis {
+                -inset
+            } else {
+                inset
+            }
+        }
+        (Boundary::Inflow(v), Some(c)) => {
+            let face = [v.x, v.y, v.z][c];
+            2.0 * face - inset
+        }
+    }
+}
+
+/// Normal velocity a closed face imposes, or `None` for open faces.
+fn wall_velocity(boundary: Boundary, axis: usize) -> Option<f32> {
+    match boundary {
+        Boundary::NoSlip | Boundary::FreeSlip => Some(0.0),
+        Boundary::Inflow(v) => Some([v.x, v.y, v.z][axis]),
+        Boundary::Outflow | Boundary::Periodic => None,
+    }
+}
+
+/// `set_borders` for a velocity component stored on the faces perpendicular
+/// to `axis`. Faces between a fluid and a solid cell take the obstacle's
+/// velocity and outer faces that are closed take the wall's. Open outer
+/// faces are solved for like interior ones, except that the lower face of a
+/// periodic axis repeats the upper one. Ghost faces across the other two
+/// axes are mirrored like collocated velocities.
+fn set_face_borders(
+    size: GridSize,
+    grid: &mut [f32],
+    field: Field,
+    axis: usize,
+    bc: &BoundaryConditions,
+    obstacles: &Obstacles,
+) {
+    let n = [size.x, size.y, size.z];
+    let stride = [1, size.x + 2, (size.x + 2) * (size.y + 2)];
+    let solid = &obstacles.solid;
+    let faces = [(bc.x_min, bc.x_max), (bc.y_min, bc.y_max), (bc.z_min, bc.z_max)];
+    let index = |c: [usize; 3]| IX!(size, c[0], c[1], c[2]);
+
+    // Faces touching obstacles
+    let first = |a: usize| if a == axis { 0 } else { 1 };
+    for kk in first(2)..=n[2] {
+        for jj in first(1)..=n[1] {
+            for ii in first(0)..=n[0] {
+                let ix = IX!(size, ii, jj, kk);
+                let next = ix + stride[axis];
+                grid[ix] = match (solid[ix], solid[next]) {
+                    (false, false) => continue,
+                    (true, false) => obstacles.value(field, ix),
+                    (false, true) => obstacles.value(field, next),
+                    (true, true) => 0.5 * (obstacles.value(field, ix) + obstacles.value(field, next)),
+                };
+            }
+        }
+    }
+
+    // Outer faces perpendicular to the component
+    let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+    let (min, max) = faces[axis];
+    for b in 0..=n[v] + 1 {
+        for a in 0..=n[u] + 1 {
+            let mut c = [0; 3];
+            c[u] = a;
+            c[v] = b;
+            let lo = index(c);
+            c[axis] = n[axis];
+            let hi = index(c);
+            c[axis] += 1;
+            let beyond = index(c);
+            if let Some(wall) = wall_velocity(min, axis) {
+                grid[lo] = wall;
+            } else if min == Boundary::Periodic {
+                grid[lo] = grid[hi];
+            }
+            if let Some(wall) = wall_velocity(max, axis) {
+                grid[hi] = wall;
+            }
+            grid[beyond] = grid[hi];
+        }
+    }
+
+    // Ghost faces across the other two axes
+    for &t in [u, v].iter() {
+        let (p, q) = ((t + 1) % 3, (t + 2) % 3);
+        let (min, max) = faces[t];
+        for b in 0..=n[q] + 1 {
+            for a in 0..=n[p] + 1 {
+                let mut c = [0; 3];
+                c[p] = a;
+                c[q] = b;
+                let ghost_lo = index(c);
+                c[t] = 1;
+                let inset_lo = index(c);
+                c[t] = n[t];
+                let inset_hi = index(c);
+                c[t] += 1;
+                let ghost_hi = index(c);
+                let (lo, hi) = (grid[inset_lo], grid[inset_hi]);
+                grid[ghost_lo] = ghost_value(min, field, t, lo, hi);
+                grid[ghost_hi] = ghost_value(max, field, t, hi, lo);
+            }
+        }
+    }
+}
+
+/// Copies the interior cells next to each face of the `periodic` axes into
+/// the ghost cells across the opposite face, so that the 7-point stencil
+/// wraps around. Only the ghost cells facing interior cells are updated.
+pub(crate) fn wrap_periodic(size: GridSize, grid: &mut [f32], periodic: [bool; 3]) {
+    let n = [size.x, size.y, size.z];
+    for axis in (0..3).filter(|&axis| periodic[axis]) {
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+        for b in 1..=n[v] {
+            for a in 1..=n[u] {
+                let mut c = [0; 3];
+                c[u] = a;
+                c[v] = b;
+                let ghost_lo = IX!(size, c[0], c[1], c[2]);
+                c[axis] = 1;
+                let first = IX!(size, c[0], c[1], c[2]);
+                c[axis] = n[axis];
+                let last = IX!(size, c[0], c[1], c[2]);
+                c[axis] += 1;
+                let ghost_hi = IX!(size, c[0], c[1], c[2]);
+                grid[ghost_lo] = grid[last];
+                grid[ghost_hi] = grid[first];
+            }
+        }
+    }
+}
+
+/// Fills the ghost cells of `grid` according to `bc`, and the solid cells
+/// with the velocity of the obstacle occupying them (zero for scalars).
+pub(crate) fn set_borders(
+    size: GridSize,
+    grid: &mut [f32],
+    field: Field,
+    bc: &BoundaryConditions,
+    obstacles: &Obstacles,
+) {
+    if let Some(axis) = field.face_axis() {
+        return set_face_borders(size, grid, field, axis, bc, obstacles);
+    }
+    for (ix, &solid) in obstacles.solid.iter().enumerate() {
+        if solid {
+            grid[ix] = obstacles.value(field, ix);
+        }
+    }
+
+    let max_x = size.x+1;
+    let max_y = size.y+1;
+    let max_z = size.z+1;
+
+    // TOP - BOTTOM WALLS
+    for ii in 0..max_x {
+        for kk in 0..max_z {
+            let ix_top = IX!(size, ii, max_y, kk);
+            let ix_top_inset = IX!(size, ii, max_y-1, kk);
+            let ix_bot = IX!(size, ii, 0, kk);
+            let ix_bot_inset = IX!(size, ii, 1, kk);
+            let (top, bot) = (grid[ix_top_inset], grid[ix_bot_inset]);
+            grid[ix_top] = ghost_value(bc.y_max, field, 1, top, bot);
+            grid[ix_bot] = ghost_value(bc.y_min, field, 1, bot, top);
+        }
+    }
+    // SIDE WALLS
+    for jj in 0..max_y {
+        for kk in 0..max_z {
+            let ix_left = IX!(size, 0, jj, kk);
+            let ix_left_inset = IX!(size, 1, jj, kk);
+            let ix_right = IX!(size, max_x, jj, kk);
+            let ix_right_inset = IX!(size, max_x-1, jj, kk);
+            let (left, right) = (grid[ix_left_inset], grid[ix_right_inset]);
+            grid[ix_left] = ghost_value(bc.x_min, field, 0, left, right);
+            grid[ix_right] = ghost_value(bc.x_max, field, 0, right, left);
+        }
+    }
+    // BACK - FRONT WALLS
+    for ii in 0..max_x {
+        for jj in 0..max_y {
+            let ix_front = IX!(size, ii, jj, 0);
+            let ix_front_inset = IX!(size, ii, jj, 1);
+            let ix_back = IX!(size, ii, jj, max_z);
+            let ix_back_inset = IX!(size, ii, jj, max_z-1);
+            let (front, back) = (grid[ix_front_inset], grid[ix_back_inset]);
+            grid[ix_front] = ghost_value(bc.z_min, field, 2, front, back);
+            grid[ix_back] = ghost_value(bc.z_max, field, 2, back, front);
+        }
+    }
+
+    // For the 12 edges of the 3d grid. Each takes the mean of the two ghost
+    // cells beside it, never of its neighbors along the edge, which would
+    // carry values along the edge past solid cells.
+    for ii in 1..max_x {
+        grid[IX!(size, ii, 0, 0)] = 0.5 * (grid[IX!(size, ii, 1, 0)] + grid[IX!(size, ii, 0, 1)]);
+        grid[IX!(size, ii, max_y, 0)] = 0.5 * (grid[IX!(size, ii, max_y-1, 0)] + grid[IX!(size, ii, max_y, 1)]);
+        grid[IX!(size, ii, 0, max_z)] = 0.5 * (grid[IX!(size, ii, 0, max_z-1)] + grid[IX!(size, ii, 1, max_z)]);
+        grid[IX!(size, ii, max_y, max_z)] =
+            0.5 * (grid[IX!(size, ii, max_y-1, max_z)] + grid[IX!(size, ii, max_y, max_z-1)]);
+    }
+    // The 8 vertices of the 3d grid
+    //grid[IX!(size, 0, 0, 0)] = (grid[IX!(size, 1, 0, size.z - 1)] + grid[IX!(size, 0, 1, size.z - 1)]);
+
+}
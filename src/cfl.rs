@@ -0,0 +1,78 @@
+//! Splitting a frame into as many steps as the velocity needs.
+//!
+//! The backtrace of a step that moves the fluid across many cells skips
+//! over whatever lies between, and is clamped at the edge of the grid. The
+//! CFL number, the largest number of cells the fluid crosses along an axis
+//! in one step, measures how far that goes; keeping it under
+//! `SolverConfig::max_cfl` keeps the steps honest however fast the fluid
+//! moves.
+
+use config::SolverConfig;
+use fluid::{step_fluid, StepStats};
+use types::*;
+
+/// Diagnostics returned by `step_frame`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameStats {
+    /// Number of steps the frame was split into.
+    pub substeps: usize,
+    /// Largest CFL number of any of the steps, measured before each step.
+    pub cfl: f32,
+    /// Stats of the last step.
+    pub last_step: StepStats,
+}
+
+/// Largest speed in cells per second along any axis.
+fn max_speed(grid: &WindGrid) -> f32 {
+    let size = grid.size;
+    let n = [size.x as f32, size.y as f32, size.z as f32];
+    let velocity = [&grid.x_vel, &grid.y_vel, &grid.z_vel];
+    (0..3)
+        .map(|a| velocity[a].iter().fold(0.0f32, |max, v| max.max(v.abs())) * n[a])
+        .fold(0.0, f32::max)
+}
+
+/// Fraction of a step a frame may run over before it takes one more.
+const STEP_SLACK: f32 = 1e-4;
+
+impl WindGrid {
+    /// Largest number of cells the fluid would cross along any axis in a
+    /// step of `dt` seconds.
+    pub fn cfl_number(&self, dt: f32) -> f32 {
+        dt * max_speed(self)
+    }
+}
+
+/// Advances `grid` by `frame_time` seconds in steps of at most `config.dt`,
+/// each short enough to keep the CFL number under `config.max_cfl`. The
+/// velocity is measured again before every step, and the time left is
+/// split evenly over the steps it still needs, so a frame never ends with
+/// a sliver of a step. At most `config.max_substeps` steps are taken, and
+/// always at least one; past that the last ones exceed the limit.
+pub fn step_frame(grid: &mut WindGrid, config: &SolverConfig, frame_time: f32) -> FrameStats {
+    assert!(
+        frame_time.is_finite() && frame_time >= 0.0,
+        "frame time must be a non-negative finite number"
+    );
+    let mut stats = FrameStats::default();
+    let mut remaining = frame_time;
+    while remaining > 0.0 {
+        let left = config.max_substeps.saturating_sub(stats.substeps).max(1);
+        let speed = max_speed(grid);
+        // Rounding leaves frames of a whole number of steps a hair over it,
+        // which mustn't cost a whole extra step
+        let needed = ((remaining * speed / config.max_cfl).max(remaining / config.dt) - STEP_SLACK).ceil();
+        let steps = (needed as usize).clamp(1, left);
+        let dt = if steps == 1 { remaining } else { remaining / steps as f32 };
+
+        let step_config = SolverConfig { dt, ..config.clone() };
+        stats.last_step = step_fluid(grid, &step_config);
+        stats.cfl = stats.cfl.max(dt * speed);
+        stats.substeps += 1;
+        remaining -= dt;
+        if steps == 1 {
+            break;
+        }
+    }
+    stats
+}
@@ -0,0 +1,220 @@
+//! Preconditioned conjugate gradient for the same system `linear_solver`
+//! relaxes: `denominator * x - diff_rate * (sum of the 6 neighbors) = prev`
+//! over the interior cells, where `diff_rate` may differ per axis. The
+//! ghost cells of `x` are held fixed except across periodic axes, where
+//! they wrap around. Solid cells are left out of the system and act as
+//! walls for their neighbors.
+
+use boundary::wrap_periodic;
+use config::Preconditioner;
+use fluid::{fluid_neighbors, SolveStats};
+use types::*;
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+}
+
+/// Diagonal of the operator, which shrinks next to solid cells. Zero in
+/// solid and ghost cells.
+fn diagonal(size: GridSize, solid: &[bool], rates: [f32; 3], denominator: f32) -> Vec<f32> {
+    let mut diagonal = vec![0.0; size.size_1d()];
+    for kk in 1..=size.z {
+        for jj in 1..=size.y {
+            for ii in 1..=size.x {
+                let ix = IX!(size, ii, jj, kk);
+                if !solid[ix] {
+                    let (_, walls) = fluid_neighbors(size, &diagonal, solid, ix, rates);
+                    diagonal[ix] = denominator - walls;
+                }
+            }
+        }
+    }
+    diagonal
+}
+
+/// `out = diagonal * v - rates * neighbors(v)` on the fluid cells.
+/// Solid cells of `v` are expected to be zero, and ghost cells too unless
+/// they wrap around.
+fn apply_operator(size: GridSize, v: &[f32], out: &mut [f32], diagonal: &[f32], rates: [f32; 3]) {
+    for kk in 1..=size.z {
+        for jj in 1..=size.y {
+            for ii in 1..=size.x {
+                let ix = IX!(size, ii, jj, kk);
+                let neighbors = rates[0] * (v[ix - 1] + v[ix + 1])
+                    + rates[1] * (v[IX!(size, ii, jj - 1, kk)] + v[IX!(size, ii, jj + 1, kk)])
+                    + rates[2] * (v[IX!(size, ii, jj, kk - 1)] + v[IX!(size, ii, jj, kk + 1)]);
+                out[ix] = if diagonal[ix] > 0.0 {
+                    diagonal[ix] * v[ix] - neighbors
+                } else {
+                    0.0
+                };
+            }
+        }
+    }
+}
+
+/// Diagonal of the incomplete Cholesky factor, stored inverted. Ghost and
+/// solid cells stay zero so that terms coupling to them drop out of the
+/// sweeps.
+fn incomplete_cholesky(size: GridSize, diagonal: &[f32], rates: [f32; 3]) -> Vec<f32> {
+    let mut precon = vec![0.0; size.size_1d()];
+    for kk in 1..=size.z {
+        for jj in 1..=size.y {
+            for ii in 1..=size.x {
+                let ix = IX!(size, ii, jj, kk);
+                if diagonal[ix] <= 0.0 {
+                    continue;
+                }
+                let px = rates[0] * precon[ix - 1];
+                let py = rates[1] * precon[IX!(size, ii, jj - 1, kk)];
+                let pz = rates[2] * precon[IX!(size, ii, jj, kk - 1)];
+                let mut e = diagonal[ix] - px * px - py * py - pz * pz;
+                if e < 0.25 * diagonal[ix] {
+                    e = diagonal[ix];
+                }
+                precon[ix] = 1.0 / e.sqrt();
+            }
+        }
+    }
+    precon
+}
+
+/// Solves `L L^T z = r` with the factor from `incomplete_cholesky`.
+/// `q` is scratch space; both it and `z` must have zero ghost cells.
+fn apply_incomplete_cholesky(
+    size: GridSize,
+    precon: &[f32],
+    rates: [f32; 3],
+    r: &[f32],
+    q: &mut [f32],
+    z: &mut [f32],
+) {
+    // Forward substitution
+    for kk in 1..=size.z {
+        for jj in 1..=size.y {
+            for ii in 1..=size.x {
+                let ix = IX!(size, ii, jj, kk);
+                let ix_y = IX!(size, ii, jj - 1, kk);
+                let ix_z = IX!(size, ii, jj, kk - 1);
+                let t = r[ix]
+                    + rates[0] * precon[ix - 1] * q[ix - 1]
+                    + rates[1] * precon[ix_y] * q[ix_y]
+                    + rates[2] * precon[ix_z] * q[ix_z];
+                q[ix] = t * precon[ix];
+            }
+        }
+    }
+    // Back substitution
+    for kk in (1..=size.z).rev() {
+        for jj in (1..=size.y).rev() {
+            for ii in (1..=size.x).rev() {
+                let ix = IX!(size, ii, jj, kk);
+                let t = q[ix]
+                    + precon[ix]
+                        * (rates[0] * z[ix + 1]
+                            + rates[1] * z[IX!(size, ii, jj + 1, kk)]
+                            + rates[2] * z[IX!(size, ii, jj, kk + 1)]);
+                z[ix] = t * precon[ix];
+            }
+        }
+    }
+}
+
+fn rms(size: GridSize, r: &[f32]) -> f32 {
+    (dot(r, r) / (size.x * size.y * size.z) as f32).sqrt()
+}
+
+/// Runs at most `iterations` conjugate gradient iterations, stopping early
+/// once the residual drops below `tolerance` if that is non-zero. The
+/// incomplete Cholesky preconditioner leaves out the coupling across the
+/// `periodic` axes.
+#[allow(clippy::too_many_arguments)]
+pub fn solve(
+    size: GridSize,
+    grid: &mut [f32],
+    prev_grid: &[f32],
+    solid: &[bool],
+    rates: [f32; 3],
+    denominator: f32,
+    iterations: usize,
+    tolerance: f32,
+    preconditioner: Preconditioner,
+    periodic: [bool; 3],
+) -> SolveStats {
+    let len = size.size_1d();
+    let diagonal = diagonal(size, solid, rates, denominator);
+    wrap_periodic(size, grid, periodic);
+
+    // Initial residual, using the fixed ghost values of `grid`
+    let mut r = vec![0.0; len];
+    for kk in 1..=size.z {
+        for jj in 1..=size.y {
+            for ii in 1..=size.x {
+                let ix = IX!(size, ii, jj, kk);
+                if diagonal[ix] <= 0.0 {
+                    continue;
+                }
+                let (neighbors, _) = fluid_neighbors(size, grid, solid, ix, rates);
+                r[ix] = prev_grid[ix] - (diagonal[ix] * grid[ix] - neighbors);
+            }
+        }
+    }
+    let mut residual = rms(size, &r);
+    if residual == 0.0 || residual < tolerance {
+        return SolveStats { iterations: 0, residual };
+    }
+
+    let precon = match preconditioner {
+        Preconditioner::IncompleteCholesky => incomplete_cholesky(size, &diagonal, rates),
+        Preconditioner::Jacobi => Vec::new(),
+    };
+    let mut scratch = vec![0.0; len];
+    let mut z = vec![0.0; len];
+    let mut precondition = |r: &[f32], z: &mut [f32]| match preconditioner {
+        Preconditioner::Jacobi => {
+            for ((z, r), d) in z.iter_mut().zip(r.iter()).zip(diagonal.iter()) {
+                *z = if *d > 0.0 { r / d } else { 0.0 };
+            }
+        }
+        Preconditioner::IncompleteCholesky => {
+            apply_incomplete_cholesky(size, &precon, rates, r, &mut scratch, z)
+        }
+    };
+
+    precondition(&r, &mut z);
+    let mut d = z.clone();
+    let mut q = vec![0.0; len];
+    let mut delta = dot(&r, &z);
+
+    for iteration in 1..=iterations {
+        // Stepping `grid` along `d` keeps its wrapped ghost cells in step
+        wrap_periodic(size, &mut d, periodic);
+        apply_operator(size, &d, &mut q, &diagonal, rates);
+        let dq = dot(&d, &q);
+        if dq <= 0.0 {
+            return SolveStats { iterations: iteration - 1, residual };
+        }
+        let alpha = delta / dq;
+        for ix in 0..len {
+            grid[ix] += alpha * d[ix];
+            r[ix] -= alpha * q[ix];
+        }
+        residual = rms(size, &r);
+        if residual < tolerance || iteration == iterations {
+            return SolveStats { iterations: iteration, residual };
+        }
+
+        precondition(&r, &mut z);
+        let delta_new = dot(&r, &z);
+        if delta_new <= 0.0 {
+            // Converged below what f32 can represent
+            return SolveStats { iterations: iteration, residual };
+        }
+        let beta = delta_new / delta;
+        delta = delta_new;
+        for ix in 0..len {
+            d[ix] = z[ix] + beta * d[ix];
+        }
+    }
+    SolveStats { iterations, residual }
+}
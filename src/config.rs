@@ -0,0 +1,238 @@
+use advection::{AdvectionScheme, Backtrace};
+use boundary::BoundaryConditions;
+use interpolation::Interpolation;
+use types::Vel;
+use std::error::Error;
+use std::fmt;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Preconditioner used by `LinearSolver::ConjugateGradient`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Preconditioner {
+    /// Divides by the diagonal. Cheap, but converges noticeably slower.
+    Jacobi,
+    /// Zero fill-in incomplete Cholesky factorisation of the 7-point stencil.
+    IncompleteCholesky,
+}
+
+/// Method used for the implicit solves in the diffusion and projection steps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum LinearSolver {
+    /// Gauss-Seidel relaxation. Cheap per sweep, but slow to remove
+    /// large-scale error.
+    GaussSeidel,
+    /// Preconditioned conjugate gradient. Needs far fewer iterations than
+    /// Gauss-Seidel on large grids. Converging it only makes the velocity
+    /// divergence free with `VelocityLayout::Staggered`.
+    ConjugateGradient(Preconditioner),
+    /// Geometric multigrid V-cycles. Each iteration is one V-cycle.
+    Multigrid,
+}
+
+/// Order in which Gauss-Seidel visits the cells, both in
+/// `LinearSolver::GaussSeidel` and as the multigrid smoother.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum SweepOrder {
+    /// Row by row, using each updated value immediately.
+    Lexicographic,
+    /// Checkerboard order: all cells with even `x + y + z` first, then the
+    /// odd ones. Runs on all cores with the `parallel` feature.
+    RedBlack,
+}
+
+/// Boussinesq buoyancy: hot fluid rises and dense smoke sinks. The force
+/// acts along `up`, whatever the grid's orientation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Buoyancy {
+    /// Temperature at which the fluid neither rises nor sinks.
+    pub ambient_temperature: f32,
+    /// Upward acceleration in m/s² per degree above `ambient_temperature`.
+    pub lift: f32,
+    /// Downward acceleration in m/s² per unit of density.
+    pub weight: f32,
+    /// World direction of up. Need not be normalized.
+    pub up: [f32; 3],
+}
+
+impl Default for Buoyancy {
+    /// No buoyancy, with the world's +y axis up.
+    fn default() -> Buoyancy {
+        Buoyancy { ambient_temperature: 0.0, lift: 0.0, weight: 0.0, up: [0.0, 1.0, 0.0] }
+    }
+}
+
+impl Buoyancy {
+    pub fn is_enabled(&self) -> bool {
+        self.lift != 0.0 || self.weight != 0.0
+    }
+}
+
+/// Parameters for one `step_fluid` call.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SolverConfig {
+    /// Time step in seconds.
+    pub dt: f32,
+    /// Diffusion coefficient of the density and temperature in m²/s.
+    pub diffusion: f32,
+    /// Kinematic viscosity of the velocity field in m²/s.
+    pub viscosity: f32,
+    /// Maximum number of Gauss-Seidel sweeps, conjugate gradient iterations
+    /// or multigrid V-cycles per linear solve.
+    pub iterations: usize,
+    /// Residual below which a linear solve stops early. Zero always runs
+    /// `iterations` sweeps.
+    pub tolerance: f32,
+    pub sweep_order: SweepOrder,
+    pub diffusion_solver: LinearSolver,
+    pub pressure_solver: LinearSolver,
+    pub boundary: BoundaryConditions,
+    /// Fraction of density lost per second.
+    pub dissipation: f32,
+    /// Strength of the vorticity confinement force, which keeps small
+    /// eddies from being smoothed away. Zero disables it.
+    pub vorticity: f32,
+    /// Disabled by default.
+    pub buoyancy: Buoyancy,
+    /// Constant acceleration of every fluid cell in world space (m/s²), such
+    /// as gravity or a prevailing wind. Inside closed walls the projection cancels it, so it
+    /// only drives a flow through open faces.
+    pub uniform_force: Vel,
+    pub velocity_advection: AdvectionScheme,
+    pub density_advection: AdvectionScheme,
+    pub temperature_advection: AdvectionScheme,
+    /// How every advection step samples the field it carries.
+    pub interpolation: Interpolation,
+    /// How every advection step traces the velocity back in time.
+    pub backtrace: Backtrace,
+    /// Largest number of cells the fluid may cross along an axis in one
+    /// step of `step_frame`, which takes steps shorter than `dt` to stay
+    /// under it.
+    pub max_cfl: f32,
+    /// Most steps `step_frame` splits a frame into.
+    pub max_substeps: usize,
+}
+
+impl Default for SolverConfig {
+    fn default() -> SolverConfig {
+        SolverConfig {
+            dt: 0.01,
+            diffusion: 0.1,
+            viscosity: 0.1,
+            iterations: 1,
+            tolerance: 0.0,
+            sweep_order: SweepOrder::Lexicographic,
+            diffusion_solver: LinearSolver::GaussSeidel,
+            pressure_solver: LinearSolver::GaussSeidel,
+            boundary: BoundaryConditions::default(),
+            dissipation: 0.0,
+            vorticity: 0.0,
+            buoyancy: Buoyancy::default(),
+            uniform_force: Vel::default(),
+            velocity_advection: AdvectionScheme::SemiLagrangian,
+            density_advection: AdvectionScheme::SemiLagrangian,
+            temperature_advection: AdvectionScheme::SemiLagrangian,
+            interpolation: Interpolation::Linear,
+            backtrace: Backtrace::Euler,
+            max_cfl: 1.0,
+            max_substeps: 16,
+        }
+    }
+}
+
+impl SolverConfig {
+    /// Creates a config with the given time step and the default values for
+    /// everything else.
+    pub fn new(dt: f32) -> Result<SolverConfig, ConfigError> {
+        let config = SolverConfig {
+            dt,
+            ..SolverConfig::default()
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks that every parameter is in range. Configs built with struct
+    /// literals or deserialized from disk should be checked with this before
+    /// use; `FluidSolver` does so automatically.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if !(self.dt.is_finite() && self.dt > 0.0) {
+            return Err(ConfigError::new("dt", "must be a positive finite number"));
+        }
+        if !(self.diffusion.is_finite() && self.diffusion >= 0.0) {
+            return Err(ConfigError::new("diffusion", "must be a non-negative finite number"));
+        }
+        if !(self.viscosity.is_finite() && self.viscosity >= 0.0) {
+            return Err(ConfigError::new("viscosity", "must be a non-negative finite number"));
+        }
+        if self.iterations == 0 {
+            return Err(ConfigError::new("iterations", "must be at least 1"));
+        }
+        if !(self.tolerance.is_finite() && self.tolerance >= 0.0) {
+            return Err(ConfigError::new("tolerance", "must be a non-negative finite number"));
+        }
+        if !(self.dissipation.is_finite() && self.dissipation >= 0.0) {
+            return Err(ConfigError::new("dissipation", "must be a non-negative finite number"));
+        }
+        if !(self.vorticity.is_finite() && self.vorticity >= 0.0) {
+            return Err(ConfigError::new("vorticity", "must be a non-negative finite number"));
+        }
+        if !self.buoyancy.ambient_temperature.is_finite() {
+            return Err(ConfigError::new(
+                "buoyancy.ambient_temperature",
+                "must be a finite number",
+            ));
+        }
+        if !(self.buoyancy.lift.is_finite() && self.buoyancy.lift >= 0.0) {
+            return Err(ConfigError::new("buoyancy.lift", "must be a non-negative finite number"));
+        }
+        if !(self.buoyancy.weight.is_finite() && self.buoyancy.weight >= 0.0) {
+            return Err(ConfigError::new("buoyancy.weight", "must be a non-negative finite number"));
+        }
+        let up = self.buoyancy.up;
+        if !(up.iter().all(|u| u.is_finite()) && up.iter().any(|&u| u != 0.0)) {
+            return Err(ConfigError::new("buoyancy.up", "must be a finite non-zero direction"));
+        }
+        let force = self.uniform_force;
+        if !(force.x.is_finite() && force.y.is_finite() && force.z.is_finite()) {
+            return Err(ConfigError::new("uniform_force", "must be finite"));
+        }
+        if !(self.max_cfl.is_finite() && self.max_cfl > 0.0) {
+            return Err(ConfigError::new("max_cfl", "must be a positive finite number"));
+        }
+        if self.max_substeps == 0 {
+            return Err(ConfigError::new("max_substeps", "must be at least 1"));
+        }
+        if let Some((face, reason)) = self.boundary.invalid_face() {
+            return Err(ConfigError::new(face, reason));
+        }
+        Ok(())
+    }
+}
+
+/// Returned when a `SolverConfig` parameter is out of range.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigError {
+    pub field: &'static str,
+    pub reason: &'static str,
+}
+
+impl ConfigError {
+    pub(crate) fn new(field: &'static str, reason: &'static str) -> ConfigError {
+        ConfigError { field, reason }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid solver config: `{}` {}", self.field, self.reason)
+    }
+}
+
+impl Error for ConfigError {}
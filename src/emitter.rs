@@ -0,0 +1,261 @@
+use transform::GridTransform;
+use types::*;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Region an `Emitter` covers, relative to its position. Lengths are in
+/// meters and points and directions in world space.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum EmitterShape {
+    Sphere { radius: f32 },
+    /// Box aligned with the grid axes, extending `half_extents` from the
+    /// position.
+    Box { half_extents: [f32; 3] },
+    /// Directional jet with its tip at the position, opening along
+    /// `direction` to `radius` at `length`.
+    Cone { direction: [f32; 3], length: f32, radius: f32 },
+    /// Segment from the position to `end`, thickened by `radius`.
+    Capsule { end: [f32; 3], radius: f32 },
+}
+
+/// Adds density, temperature and velocity to the cells inside its shape on
+/// every step, at the given rates per second.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Emitter {
+    /// World position of the anchor of the shape.
+    pub position: [f32; 3],
+    pub shape: EmitterShape,
+    /// Fraction of the shape, measured inwards from its surface, over which
+    /// the rates fade smoothly to zero. Zero gives a hard edge, one fades
+    /// all the way from the center.
+    pub falloff: f32,
+    pub density: f32,
+    pub temperature: f32,
+    /// Acceleration in m/s².
+    pub velocity: Vel,
+    pub enabled: bool,
+}
+
+impl Emitter {
+    /// An enabled emitter with a hard edge and all rates zero.
+    pub fn new(position: [f32; 3], shape: EmitterShape) -> Emitter {
+        Emitter {
+            position,
+            shape,
+            falloff: 0.0,
+            density: 0.0,
+            temperature: 0.0,
+            velocity: Vel::default(),
+            enabled: true,
+        }
+    }
+
+    /// The emitter in cell coordinates and grid velocity units.
+    fn to_grid(self, transform: &GridTransform, size: GridSize) -> Emitter {
+        let h = transform.cell_size;
+        let shape = match self.shape {
+            EmitterShape::Sphere { radius } => EmitterShape::Sphere { radius: radius / h },
+            EmitterShape::Box { half_extents } => EmitterShape::Box {
+                half_extents: [half_extents[0] / h, half_extents[1] / h, half_extents[2] / h],
+            },
+            EmitterShape::Cone { direction, length, radius } => EmitterShape::Cone {
+                direction: transform.direction_to_grid(direction),
+                length: length / h,
+                radius: radius / h,
+            },
+            EmitterShape::Capsule { end, radius } => EmitterShape::Capsule {
+                end: transform.world_to_cell(end),
+                radius: radius / h,
+            },
+        };
+        Emitter {
+            position: transform.world_to_cell(self.position),
+            shape,
+            velocity: transform.velocity_to_grid(size, self.velocity),
+            ..self
+        }
+    }
+
+    /// Corners of a box around the shape.
+    fn bounds(&self) -> ([f32; 3], [f32; 3]) {
+        let p = self.position;
+        let (a, b, pad) = match self.shape {
+            EmitterShape::Sphere { radius } => (p, p, [radius; 3]),
+            EmitterShape::Box { half_extents } => (p, p, half_extents),
+            EmitterShape::Cone { direction, length, radius } => {
+                let d = normalize(direction);
+                let tip = [p[0] + d[0] * length, p[1] + d[1] * length, p[2] + d[2] * length];
+                (p, tip, [radius; 3])
+            }
+            EmitterShape::Capsule { end, radius } => (p, end, [radius; 3]),
+        };
+        let mut min = [0.0; 3];
+        let mut max = [0.0; 3];
+        for axis in 0..3 {
+            min[axis] = a[axis].min(b[axis]) - pad[axis];
+            max[axis] = a[axis].max(b[axis]) + pad[axis];
+        }
+        (min, max)
+    }
+
+    /// Distance of `c` from the position scaled so that the surface of the
+    /// shape is at 1.
+    fn scaled_distance(&self, c: [f32; 3]) -> f32 {
+        let p = self.position;
+        let v = [c[0] - p[0], c[1] - p[1], c[2] - p[2]];
+        match self.shape {
+            EmitterShape::Sphere { radius } => length(v) / radius,
+            EmitterShape::Box { half_extents } => (0..3)
+                .map(|axis| v[axis].abs() / half_extents[axis])
+                .fold(0.0, f32::max),
+            EmitterShape::Cone { direction, length: height, radius } => {
+                let d = normalize(direction);
+                let along = dot(v, d);
+                if along < 0.0 || along > height {
+                    return f32::INFINITY;
+                }
+                let across = length([v[0] - d[0] * along, v[1] - d[1] * along, v[2] - d[2] * along]);
+                let local_radius = radius * along / height;
+                if local_radius > 0.0 {
+                    across / local_radius
+                } else if across == 0.0 {
+                    1.0
+                } else {
+                    f32::INFINITY
+                }
+            }
+            EmitterShape::Capsule { end, radius } => {
+                let axis = [end[0] - p[0], end[1] - p[1], end[2] - p[2]];
+                let span = dot(axis, axis);
+                let t = if span > 0.0 { (dot(v, axis) / span).clamp(0.0, 1.0) } else { 0.0 };
+                length([v[0] - axis[0] * t, v[1] - axis[1] * t, v[2] - axis[2] * t]) / radius
+            }
+        }
+    }
+
+    /// How strongly the emitter acts on a point: 1 inside, fading smoothly
+    /// to 0 at the surface over the falloff band, and 0 outside.
+    fn weight(&self, c: [f32; 3]) -> f32 {
+        let d = self.scaled_distance(c);
+        if d.is_nan() || d > 1.0 {
+            return 0.0;
+        }
+        let edge = 1.0 - d;
+        if edge >= self.falloff {
+            return 1.0;
+        }
+        let t = edge / self.falloff;
+        t * t * (3.0 - 2.0 * t)
+    }
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn length(a: [f32; 3]) -> f32 {
+    dot(a, a).sqrt()
+}
+
+fn normalize(a: [f32; 3]) -> [f32; 3] {
+    let l = length(a);
+    if l > 0.0 {
+        [a[0] / l, a[1] / l, a[2] / l]
+    } else {
+        [0.0, 1.0, 0.0]
+    }
+}
+
+/// Identifies an emitter in a `WindGrid`. Stays invalid once the emitter is
+/// removed, even if its slot is reused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct EmitterHandle {
+    index: usize,
+    generation: u32,
+}
+
+/// The emitters of a grid, addressed by handle.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Emitters {
+    slots: Vec<(u32, Option<Emitter>)>,
+}
+
+impl Emitters {
+    pub fn add(&mut self, emitter: Emitter) -> EmitterHandle {
+        if let Some(index) = self.slots.iter().position(|slot| slot.1.is_none()) {
+            let slot = &mut self.slots[index];
+            slot.1 = Some(emitter);
+            return EmitterHandle { index, generation: slot.0 };
+        }
+        self.slots.push((0, Some(emitter)));
+        EmitterHandle { index: self.slots.len() - 1, generation: 0 }
+    }
+
+    pub fn get(&self, handle: EmitterHandle) -> Option<&Emitter> {
+        match self.slots.get(handle.index) {
+            Some(&(generation, Some(ref emitter))) if generation == handle.generation => Some(emitter),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, handle: EmitterHandle) -> Option<&mut Emitter> {
+        match self.slots.get_mut(handle.index) {
+            Some(&mut (generation, Some(ref mut emitter))) if generation == handle.generation => {
+                Some(emitter)
+            }
+            _ => None,
+        }
+    }
+
+    /// Removes the emitter and returns it, or `None` if the handle is stale.
+    pub fn remove(&mut self, handle: EmitterHandle) -> Option<Emitter> {
+        self.get(handle)?;
+        let slot = &mut self.slots[handle.index];
+        slot.0 = slot.0.wrapping_add(1);
+        slot.1.take()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Emitter> {
+        self.slots.iter().filter_map(|slot| slot.1.as_ref())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    /// Calls `f(emitter, ix, weight)` for every enabled emitter and every
+    /// fluid cell it reaches, with the emitter converted to grid units.
+    pub(crate) fn for_each_cell<F>(&self, size: GridSize, transform: &GridTransform, solid: &[bool], mut f: F)
+    where
+        F: FnMut(&Emitter, usize, f32),
+    {
+        let n = [size.x, size.y, size.z];
+        for emitter in self.iter().filter(|e| e.enabled) {
+            let emitter = &emitter.to_grid(transform, size);
+            let (min, max) = emitter.bounds();
+            let mut lo = [0; 3];
+            let mut hi = [0; 3];
+            for axis in 0..3 {
+                lo[axis] = min[axis].ceil().max(1.0) as usize;
+                hi[axis] = max[axis].floor().min(n[axis] as f32).max(0.0) as usize;
+            }
+            for kk in lo[2]..=hi[2] {
+                for jj in lo[1]..=hi[1] {
+                    for ii in lo[0]..=hi[0] {
+                        let ix = IX!(size, ii, jj, kk);
+                        if solid[ix] {
+                            continue;
+                        }
+                        let weight = emitter.weight([ii as f32, jj as f32, kk as f32]);
+                        if weight > 0.0 {
+                            f(emitter, ix, weight);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
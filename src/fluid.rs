@@ -1,133 +1,287 @@
+use advection::{self, AdvectionScheme, Backtrace};
+use boundary::{set_borders, wrap_periodic, Field};
+use cg;
+use config::{LinearSolver, SolverConfig, SweepOrder};
+use emitter::Emitters;
+use forces;
+use interpolation::{self, Interpolation};
+use mac::{self, VelocityLayout};
+use multigrid;
+use obstacle::Obstacles;
+use parallel;
+use transform::GridTransform;
 use types::*;
-/// Macro for indexing into a 1D array using 3D coordinates.
-macro_rules! IX {
-    ( $x: expr, $y: expr,  $z: expr ) => {{ $x as usize + (X_SIZE + 2) * ($y as usize + (Y_SIZE + 2) * $z as usize) }};
+
+/// Outcome of an iterative linear solve.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SolveStats {
+    /// Number of sweeps that were run.
+    pub iterations: usize,
+    /// Root mean square residual over the interior cells after the last sweep.
+    pub residual: f32,
 }
 
-/// * `b` - The type of border. 1 for vertical vel walls, 2 for side vel walls,
-///   3 for back-front walls, 0 for dens.
-fn set_borders(grid: &mut Box<[f32]>, b: u8) {
-    let max_x = X_SIZE+1;
-    let max_y = Y_SIZE+1;
-    let max_z = Z_SIZE+1;
-
-    for ii in 0..max_x {
-        for kk in 0..max_z {
-            let ix_top = IX!(ii, max_y, kk);
-            let ix_top_inset = IX!(ii, max_y-1, kk);
-            let ix_bot = IX!(ii, 0, kk);
-            let ix_bot_inset = IX!(ii, 1, kk);
-            grid[ix_top] = {
-                if b == 2 {
-                    -grid[ix_top_inset]
-                } else {
-                    grid[ix_top_inset]
-                }
-            };
-            grid[ix_bot] = {
-                if b == 2 {
-                    -grid[ix_bot_inset]
-                } else {
-                    grid[ix_bot_inset]
-                }
-            };
+/// Per-step diagnostics returned by `step_fluid`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StepStats {
+    /// The pressure solve of the final projection, which decides how close
+    /// to divergence-free the velocity field ends up.
+    pub pressure: SolveStats,
+}
+
+/// Sum of the 6 neighbors of `ix` that are not solid, each weighted by the
+/// coupling `rates` of its axis, and the summed coupling of the solid ones.
+/// Solid neighbors act as walls with zero gradient, so each one takes its
+/// coupling off the diagonal instead.
+#[inline]
+pub(crate) fn fluid_neighbors(
+    size: GridSize,
+    grid: &[f32],
+    solid: &[bool],
+    ix: usize,
+    rates: [f32; 3],
+) -> (f32, f32) {
+    let row = size.x + 2;
+    let slab = row * (size.y + 2);
+    let values = [
+        grid[ix - 1],
+        grid[ix + 1],
+        grid[ix - row],
+        grid[ix + row],
+        grid[ix - slab],
+        grid[ix + slab],
+    ];
+    weigh_neighbors(size, solid, ix, rates, values)
+}
+
+/// `fluid_neighbors` with the neighbor values given in the order -x, +x,
+/// -y, +y, -z, +z.
+#[inline]
+fn weigh_neighbors(
+    size: GridSize,
+    solid: &[bool],
+    ix: usize,
+    rates: [f32; 3],
+    values: [f32; 6],
+) -> (f32, f32) {
+    let row = size.x + 2;
+    let slab = row * (size.y + 2);
+    let neighbors = [ix - 1, ix + 1, ix - row, ix + row, ix - slab, ix + slab];
+    let mut sum = 0.0;
+    let mut walls = 0.0;
+    for (a, (&n, &value)) in neighbors.iter().zip(values.iter()).enumerate() {
+        if solid[n] {
+            walls += rates[a / 2];
+        } else {
+            sum += rates[a / 2] * value;
         }
     }
-    // SIDE WALLS
-    for jj in 0..max_y {
-        for kk in 0..max_z {
-            let ix_left = IX!(0, jj, kk);
-            let ix_left_inset = IX!(1, jj, kk);
-            let ix_right = IX!(max_x, jj, kk);
-            let ix_right_inset = IX!(max_x-1, jj, kk);
-            grid[ix_left] = {
-                if b == 1 {
-                    -grid[ix_left_inset]
-                } else {
-                    grid[ix_left_inset]
-                }
-            };
-            grid[ix_right] = {
-                if b == 1 {
-                    -grid[ix_right_inset]
-                } else {
-                    grid[ix_right_inset]
+    (sum, walls)
+}
+
+/// Root mean square of `prev_grid - (denominator * grid - neighbors)` over
+/// the fluid cells, with the neighbors weighted by the coupling `rates` of
+/// their axis.
+pub(crate) fn residual(
+    size: GridSize,
+    grid: &[f32],
+    prev_grid: &[f32],
+    solid: &[bool],
+    rates: [f32; 3],
+    denominator: f32,
+) -> f32 {
+    let sum = parallel::sum_slices(size, |kk| {
+        let mut sum = 0.0;
+        for jj in 1..=size.y {
+            for ii in 1..=size.x {
+                let ix = IX!(size, ii, jj, kk);
+                if solid[ix] {
+                    continue;
                 }
-            };
+                let (neighbors, walls) = fluid_neighbors(size, grid, solid, ix, rates);
+                let r = prev_grid[ix] - ((denominator - walls) * grid[ix] - neighbors);
+                sum += r * r;
+            }
         }
-    }
-    // BACK - FRONT WALLS
-    for ii in 0..max_x {
-        for jj in 0..max_y {
-            let ix_front = IX!(ii, jj, 0);
-            let ix_front_inset = IX!(ii, jj, 1);
-            let ix_back = IX!(ii, jj, max_z);
-            let ix_back_inset = IX!(ii, jj, max_z-1);
-            grid[ix_front] = {
-                if b == 3 {
-                    -grid[ix_front_inset]
-                } else {
-                    grid[ix_front_inset]
+        sum
+    });
+    (sum / (size.x * size.y * size.z) as f32).sqrt()
+}
+
+/// One Gauss-Seidel sweep over the interior cells, with the neighbors
+/// weighted by the coupling `rates` of their axis. The ghost cells across
+/// the `periodic` axes are wrapped around after the sweep, or after each
+/// color of a red-black sweep.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn relax(
+    size: GridSize,
+    grid: &mut [f32],
+    prev_grid: &[f32],
+    solid: &[bool],
+    rates: [f32; 3],
+    denominator: f32,
+    order: SweepOrder,
+    periodic: [bool; 3],
+) {
+    match order {
+        SweepOrder::Lexicographic => {
+            // For each cell we get contributions from all 6 direct neighbors
+            for ii in 1..=size.x {
+                for jj in 1..=size.y  {
+                    for kk in 1..=size.z  {
+                        let ix = IX!(size, ii, jj, kk); //Index of current cell
+                        if solid[ix] {
+                            continue;
+                        }
+                        let (neighbors, walls) = fluid_neighbors(size, grid, solid, ix, rates);
+                        let diagonal = denominator - walls;
+                        if diagonal > 0.0 {
+                            grid[ix] = (prev_grid[ix] + neighbors) / diagonal;
+                        }
+                    }
                 }
-            };
-            grid[ix_back] = {
-                if b == 3 {
-                    -grid[ix_back_inset]
-                } else {
-                    grid[ix_back_inset]
+            }
+            wrap_periodic(size, grid, periodic);
+        }
+        SweepOrder::RedBlack => {
+            // Cells of one color only read cells of the other color, so each
+            // half sweep can update them in place in any order. The z-slices
+            // are split by parity so that the slices being written only read
+            // their own cells and the slices next to them.
+            let (row, slab) = (size.x + 2, (size.x + 2) * (size.y + 2));
+            for color in 0..2 {
+                for parity in 0..2 {
+                    let sweep = |kk: usize, slice: &mut [f32], below: &[f32], above: &[f32]| {
+                        let base = kk * slab;
+                        for jj in 1..=size.y {
+                            let first = 1 + (1 + jj + kk + color) % 2;
+                            for ii in (first..=size.x).step_by(2) {
+                                let ix = IX!(size, ii, jj, kk);
+                                if solid[ix] {
+                                    continue;
+                                }
+                                let i = ix - base;
+                                let values = [
+                                    slice[i - 1],
+                                    slice[i + 1],
+                                    slice[i - row],
+                                    slice[i + row],
+                                    below[i],
+                                    above[i],
+                                ];
+                                let (neighbors, walls) =
+                                    weigh_neighbors(size, solid, ix, rates, values);
+                                let diagonal = denominator - walls;
+                                if diagonal > 0.0 {
+                                    slice[i] = (prev_grid[ix] + neighbors) / diagonal;
+                                }
+                            }
+                        }
+                    };
+                    parallel::for_each_slice_of_parity(size, grid, parity, sweep);
                 }
-            };
+                wrap_periodic(size, grid, periodic);
+            }
         }
     }
-
-    // For the 12 edges of the 3d grid
-    for ii in 1..max_x-1 {
-        grid[IX!(ii, 0, 0)]= (grid[IX!(ii+1, 0, 0)]+grid[IX!(ii-1, 0, 0)]+grid[IX!(ii, 1, 0)]+grid[IX!(ii, 0, 1)])/4.0;
-        grid[IX!(ii, max_y, 0)]= (grid[IX!(ii+1, max_y, 0)]+grid[IX!(ii-1, max_y, 0)]+grid[IX!(ii, max_y-1, 0)]+grid[IX!(ii, max_y, 1)])/4.0;
-        grid[IX!(ii, 0, max_z)]= (grid[IX!(ii+1, 0, max_z)]+grid[IX!(ii-1, 0, max_z)]+grid[IX!(ii, 0, max_z-1)]+grid[IX!(ii, 1, max_z)])/4.0;
-        grid[IX!(ii, max_y, max_z)]=(grid[IX!(ii+1, max_y, max_z)]+grid[IX!(ii-1, max_y, max_z)]+grid[IX!(ii, max_y-1, max_z)]+grid[IX!(ii, max_y, max_z-1)])/4.0;
-    }
-    // The 8 vertices of the 3d grid
-    //grid[IX!(0, 0, 0)] = (grid[IX!(1, 0, Z_SIZE - 1)] + grid[IX!(0, 1, Z_SIZE - 1)]);
-
 }
 
+/// Gauss-Seidel relaxation. Runs `config.iterations` sweeps, or stops early
+/// once the residual drops below `config.tolerance` if that is non-zero.
+#[allow(clippy::too_many_arguments)]
 fn linear_solver(
+    size: GridSize,
     grid: &mut Box<[f32]>,
-    prev_grid: &Box<[f32]>,
+    prev_grid: &[f32],
+    obstacles: &Obstacles,
     diff_rate: f32,
     denominator: f32,
+    config: &SolverConfig,
     borders: bool,
-    b: u8,
-){    // For each cell we get contributions from all 6 direct neighbors
-    let iterations = 1;
-
-    for _ in 0..iterations {
-        for ii in 1..=X_SIZE {
-            for jj in 1..=Y_SIZE  {
-                for kk in 1..=Z_SIZE  {
-                    let ix = IX!(ii, jj, kk); //Index of current cell
-                    let ix_up = IX!(ii, jj - 1, kk); // 1 row up
-                    let ix_down = IX!(ii, jj + 1, kk); // 1 row down
-                    let ix_back = IX!(ii, jj, kk - 1); // 1 row back
-                    let ix_front = IX!(ii, jj, kk + 1); // 1 row front
-                    grid[ix] = (prev_grid[ix]
-                        + diff_rate
-                            * (grid[ix - 1]
-                                + grid[ix + 1]
-                                + grid[ix_up]
-                                + grid[ix_down]
-                                + grid[ix_back]
-                                + grid[ix_front]))
-                        / denominator;
-                }
-            }
-        }
+    field: Field,
+) -> SolveStats {
+    let (iterations, tolerance) = (config.iterations, config.tolerance);
+    for iteration in 1..=iterations {
+        relax(
+            size,
+            grid,
+            prev_grid,
+            &obstacles.solid,
+            [diff_rate; 3],
+            denominator,
+            config.sweep_order,
+            obstacles.periodic,
+        );
         if borders {
-            set_borders(grid, b)
+            set_borders(size, grid, field, &config.boundary, obstacles)
+        }
+        if tolerance > 0.0 || iteration == iterations {
+            let residual = residual(size, grid, prev_grid, &obstacles.solid, [diff_rate; 3], denominator);
+            if residual < tolerance || iteration == iterations {
+                return SolveStats { iterations: iteration, residual };
+            }
         }
     }
+    SolveStats::default()
+}
 
+/// Solves `denominator * grid - diff_rate * neighbors(grid) = prev_grid` on
+/// the interior with the chosen solver. Gauss-Seidel updates the borders
+/// after every sweep, the other solvers hold the ghost cells fixed and
+/// update them once at the end. Ghost cells across the periodic axes of
+/// `obstacles` wrap around throughout.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn solve(
+    size: GridSize,
+    grid: &mut Box<[f32]>,
+    prev_grid: &[f32],
+    obstacles: &Obstacles,
+    diff_rate: f32,
+    denominator: f32,
+    solver: LinearSolver,
+    config: &SolverConfig,
+    borders: bool,
+    field: Field,
+) -> SolveStats {
+    let stats = match solver {
+        LinearSolver::GaussSeidel => linear_solver(
+            size,
+            grid,
+            prev_grid,
+            obstacles,
+            diff_rate,
+            denominator,
+            config,
+            borders,
+            field,
+        ),
+        LinearSolver::ConjugateGradient(preconditioner) => cg::solve(
+            size,
+            grid,
+            prev_grid,
+            &obstacles.solid,
+            [diff_rate; 3],
+            denominator,
+            config.iterations,
+            config.tolerance,
+            preconditioner,
+            obstacles.periodic,
+        ),
+        LinearSolver::Multigrid => multigrid::solve(
+            size,
+            grid,
+            prev_grid,
+            &obstacles.solid,
+            diff_rate,
+            denominator,
+            config,
+            obstacles.periodic,
+        ),
+    };
+    if borders && solver != LinearSolver::GaussSeidel {
+        set_borders(size, grid, field, &config.boundary, obstacles);
+    }
+    stats
 }
 
 fn trace_backwards(
@@ -154,140 +308,329 @@ fn trace_backwards(
     (p0, p1, d0, d1)
 }
 
-/// Diffuse values with neighbors. Depends on viscosity (non-viscosity).
+/// How far the fluid now in cell `(ii, jj, kk)` moved over the last step,
+/// in cells, tracing the cell-centered `velocity` back with `backtrace`.
+fn displacement(
+    size: GridSize,
+    cell: [usize; 3],
+    ix: usize,
+    velocity: [&[f32]; 3],
+    scale: [f32; 3],
+    backtrace: Backtrace,
+) -> [f32; 3] {
+    let pos = [cell[0] as f32, cell[1] as f32, cell[2] as f32];
+    let here = [velocity[0][ix], velocity[1][ix], velocity[2][ix]];
+    backtrace.displacement(pos, here, scale, |p| {
+        [
+            interpolation::trilinear(size, velocity[0], p),
+            interpolation::trilinear(size, velocity[1], p),
+            interpolation::trilinear(size, velocity[2], p),
+        ]
+    })
+}
+
+/// Diffuse values with neighbors. Depends on viscosity (non-viscosity),
+/// in cells² per second.
 fn diffuse(
+    size: GridSize,
     grid: &mut Box<[f32]>,
-    prev_grid: &Box<[f32]>,
-    dt: f32,
+    prev_grid: &[f32],
+    obstacles: &Obstacles,
     viscosity: f32,
-    borders: bool,
-    b: u8,
+    config: &SolverConfig,
+    field: Field,
 ) {
-    let diff_rate = dt * viscosity * grid.len() as f32;
+    let diff_rate = config.dt * viscosity;
     if viscosity == 0.0 {
         return;
     }
-    linear_solver(grid, prev_grid, diff_rate, 1.0 + 6.0 * diff_rate, borders, b)
+    solve(
+        size,
+        grid,
+        prev_grid,
+        obstacles,
+        diff_rate,
+        1.0 + 6.0 * diff_rate,
+        config.diffusion_solver,
+        config,
+        true,
+        field,
+    );
 }
 
 /// Move values accross velocity fields
 #[allow(clippy::too_many_arguments)]
 fn advect(
+    size: GridSize,
     grid:  &mut Box<[f32]>,
-    prev_grid: &Box<[f32]>,
-    vx_grid: &Box<[f32]>,
-    vy_grid: &Box<[f32]>,
-    vz_grid: &Box<[f32]>,
-    dt: f32,
-    borders: bool,
-    b: u8,
+    prev_grid: &[f32],
+    vx_grid: &[f32],
+    vy_grid: &[f32],
+    vz_grid: &[f32],
+    obstacles: &Obstacles,
+    config: &SolverConfig,
+    field: Field,
 ) {
-    let dt0x = dt * X_SIZE as f32;
-    let dt0y = dt * Y_SIZE as f32;
-    let dt0z = dt * Z_SIZE as f32;
-
-    for ii in 1..=X_SIZE {
-        for jj in 1..=Y_SIZE  {
-            for kk in 1..=Z_SIZE  {
+    let solid = &obstacles.solid;
+    let dt = config.dt;
+    let dt0x = dt * size.x as f32;
+    let dt0y = dt * size.y as f32;
+    let dt0z = dt * size.z as f32;
+    let cubic = config.interpolation == Interpolation::MonotoneCubic;
+    let velocity = [vx_grid, vy_grid, vz_grid];
+
+    parallel::for_each_slice(size, grid, |kk, base, slice| {
+        for jj in 1..=size.y {
+            for ii in 1..=size.x {
                 // current cell center
-                let ix = IX!(ii, jj, kk);
+                let ix = IX!(size, ii, jj, kk);
+                if solid[ix] {
+                    continue;
+                }
 
                 // positions of and distance to adjacent cell centers to previous position of current cell center
-                let (x0, x1, r0, r1) = trace_backwards(ii as f32, dt0x * vx_grid[ix], X_SIZE as f32);
-                let (y0, y1, s0, s1) = trace_backwards(jj as f32, dt0y * vy_grid[ix], Y_SIZE as f32);
-                let (z0, z1, t0, t1) = trace_backwards(kk as f32, dt0z * vz_grid[ix], Z_SIZE as f32);
+                let moved = displacement(size, [ii, jj, kk], ix, velocity, [dt0x, dt0y, dt0z], config.backtrace);
+                let (x0, x1, r0, r1) = trace_backwards(ii as f32, moved[0], size.x as f32);
+                let (y0, y1, s0, s1) = trace_backwards(jj as f32, moved[1], size.y as f32);
+                let (z0, z1, t0, t1) = trace_backwards(kk as f32, moved[2], size.z as f32);
 
                 // all adjacent cell centers surrounding previous position (like vertices of a cube)
-                let ix000 = IX!(x0, y0, z0);
-                let ix010 = IX!(x0, y1, z0);
-                let ix100 = IX!(x1, y0, z0);
-                let ix110 = IX!(x1, y1, z0);
-                let ix001 = IX!(x0, y0, z1);
-                let ix011 = IX!(x0, y1, z1);
-                let ix111 = IX!(x1, y1, z1);
-                let ix101 = IX!(x1, y0, z1);
-
-                // value of cell is weighed average of the values of the 8 cell centers
-                grid[ix] = r0
-                    * (s0 * (t0 * prev_grid[ix000] + t1 * prev_grid[ix001])
-                        + s1 * (t0 * prev_grid[ix010] + t1 * prev_grid[ix011]))
-                    + r1 * (s0 * (t0 * prev_grid[ix100] + t1 * prev_grid[ix101])
-                        + s1 * (t0 * prev_grid[ix110] + t1 * prev_grid[ix111]));
+                let ix000 = IX!(size, x0, y0, z0);
+                let ix010 = IX!(size, x0, y1, z0);
+                let ix100 = IX!(size, x1, y0, z0);
+                let ix110 = IX!(size, x1, y1, z0);
+                let ix001 = IX!(size, x0, y0, z1);
+                let ix011 = IX!(size, x0, y1, z1);
+                let ix111 = IX!(size, x1, y1, z1);
+                let ix101 = IX!(size, x1, y0, z1);
+
+                let corners = [ix000, ix001, ix010, ix011, ix100, ix101, ix110, ix111];
+                let lo = [x0 as usize, y0 as usize, z0 as usize];
+                if cubic && interpolation::stencil_is_fluid(size, solid, lo) {
+                    slice[ix - base] = interpolation::tricubic(size, prev_grid, lo, [r1, s1, t1]);
+                } else if corners.iter().all(|&c| !solid[c]) {
+                    // value of cell is weighed average of the values of the 8 cell centers
+                    slice[ix - base] = r0
+                        * (s0 * (t0 * prev_grid[ix000] + t1 * prev_grid[ix001])
+                            + s1 * (t0 * prev_grid[ix010] + t1 * prev_grid[ix011]))
+                        + r1 * (s0 * (t0 * prev_grid[ix100] + t1 * prev_grid[ix101])
+                            + s1 * (t0 * prev_grid[ix110] + t1 * prev_grid[ix111]));
+                } else {
+                    // leave out the corners inside obstacles and reweigh the rest
+                    let weights = [
+                        r0 * s0 * t0, r0 * s0 * t1, r0 * s1 * t0, r0 * s1 * t1,
+                        r1 * s0 * t0, r1 * s0 * t1, r1 * s1 * t0, r1 * s1 * t1,
+                    ];
+                    let mut sum = 0.0;
+                    let mut total = 0.0;
+                    for (&c, &w) in corners.iter().zip(weights.iter()) {
+                        if !solid[c] {
+                            sum += w * prev_grid[c];
+                            total += w;
+                        }
+                    }
+                    slice[ix - base] = if total > 0.0 { sum / total } else { prev_grid[ix] };
+                }
             }
         }
-    }
-    if borders {
-        set_borders(grid, b);
-    }
+    });
+    set_borders(size, grid, field, &config.boundary, obstacles);
+}
+
+/// Clamps every fluid cell of `grid` to the range of the fluid cells of
+/// `prev_grid` that `advect` interpolates between for it.
+fn limit(
+    size: GridSize,
+    grid: &mut [f32],
+    prev_grid: &[f32],
+    velocity: [&[f32]; 3],
+    obstacles: &Obstacles,
+    config: &SolverConfig,
+    field: Field,
+) {
+    let solid = &obstacles.solid;
+    let dt = config.dt;
+    let scale = [dt * size.x as f32, dt * size.y as f32, dt * size.z as f32];
+    parallel::for_each_slice(size, grid, |kk, base, slice| {
+        for jj in 1..=size.y {
+            for ii in 1..=size.x {
+                let ix = IX!(size, ii, jj, kk);
+                if solid[ix] {
+                    continue;
+                }
+                let moved = displacement(size, [ii, jj, kk], ix, velocity, scale, config.backtrace);
+                let (x0, x1, _, _) = trace_backwards(ii as f32, moved[0], size.x as f32);
+                let (y0, y1, _, _) = trace_backwards(jj as f32, moved[1], size.y as f32);
+                let (z0, z1, _, _) = trace_backwards(kk as f32, moved[2], size.z as f32);
+                let mut lo = f32::INFINITY;
+                let mut hi = f32::NEG_INFINITY;
+                for &(x, y, z) in [
+                    (x0, y0, z0), (x0, y0, z1), (x0, y1, z0), (x0, y1, z1),
+                    (x1, y0, z0), (x1, y0, z1), (x1, y1, z0), (x1, y1, z1),
+                ].iter() {
+                    let c = IX!(size, x, y, z);
+                    if !solid[c] {
+                        lo = lo.min(prev_grid[c]);
+                        hi = hi.max(prev_grid[c]);
+                    }
+                }
+                if lo <= hi {
+                    slice[ix - base] = slice[ix - base].max(lo).min(hi);
+                }
+            }
+        }
+    });
+    set_borders(size, grid, field, &config.boundary, obstacles);
+}
+
+/// Carries `prev_grid` along the cell-centered `velocity` into `grid`.
+#[allow(clippy::too_many_arguments)]
+fn advect_field(
+    size: GridSize,
+    grid: &mut Box<[f32]>,
+    prev_grid: &[f32],
+    velocity: [&[f32]; 3],
+    obstacles: &Obstacles,
+    scheme: AdvectionScheme,
+    config: &SolverConfig,
+    field: Field,
+) {
+    let [vx, vy, vz] = velocity;
+    advection::advect(
+        scheme,
+        grid,
+        prev_grid,
+        config,
+        |out, from, config| advect(size, out, from, vx, vy, vz, obstacles, config, field),
+        |out, prev| limit(size, out, prev, velocity, obstacles, config, field),
+    );
 }
 
-// Forces velocity to be mass conserving
+// Forces velocity to be mass conserving. Component `a` moves the fluid
+// `n_a` cells per grid length, so divergence and pressure are taken in
+// cells, where the 7-point Laplacian has the same weight on every axis.
 #[allow(clippy::too_many_arguments)]
 fn project(
+    size: GridSize,
     vx_grid: &mut Box<[f32]>,
     vy_grid: &mut Box<[f32]>,
     vz_grid: &mut Box<[f32]>,
     prev_x: &mut Box<[f32]>,
     prev_y: &mut Box<[f32]>,
-    prev_z: &mut Box<[f32]>,
-    borders: bool,
-) {
-    for ii in 1..=X_SIZE {
-        for jj in 1..=Y_SIZE  {
-            for kk in 1..=Z_SIZE  {
-                let ix = IX!(ii, jj, kk);
-                let ix_up = IX!(ii, jj - 1, kk); // 1 row up
-                let ix_down = IX!(ii, jj + 1, kk); // 1 row down
-                let ix_back = IX!(ii, jj, kk - 1); // 1 row back
-                let ix_front = IX!(ii, jj, kk + 1); // 1 row front
-                prev_y[ix] = -0.5
-                    * ((vx_grid[ix + 1] - vx_grid[ix - 1])/X_SIZE as f32 +
-                         (vy_grid[ix_down] - vy_grid[ix_up])/Y_SIZE as f32 +
-                         (vz_grid[ix_front] - vz_grid[ix_back])/Z_SIZE as f32);
-                prev_x[ix] = 0.0;
+    obstacles: &Obstacles,
+    config: &SolverConfig,
+) -> SolveStats {
+    {
+        let (vx, vy, vz): (&[f32], &[f32], &[f32]) = (vx_grid, vy_grid, vz_grid);
+        parallel::for_each_slice(size, prev_y, |kk, base, slice| {
+            for jj in 1..=size.y {
+                for ii in 1..=size.x {
+                    let ix = IX!(size, ii, jj, kk);
+                    let ix_up = IX!(size, ii, jj - 1, kk); // 1 row up
+                    let ix_down = IX!(size, ii, jj + 1, kk); // 1 row down
+                    let ix_back = IX!(size, ii, jj, kk - 1); // 1 row back
+                    let ix_front = IX!(size, ii, jj, kk + 1); // 1 row front
+                    slice[ix - base] = -0.5
+                        * ((vx[ix + 1] - vx[ix - 1]) * size.x as f32 +
+                             (vy[ix_down] - vy[ix_up]) * size.y as f32 +
+                             (vz[ix_front] - vz[ix_back]) * size.z as f32);
+                }
             }
-        }
+        });
     }
-    if borders {
-        set_borders(prev_y, 1);
-        set_borders(prev_x, 2);
-        set_borders(prev_z, 3);
+    for p in prev_x.iter_mut() {
+        *p = 0.0;
     }
 
-    //Gauss seidel to compute gradient field x with y (ignoring z right now)
-    linear_solver(prev_x, prev_y, 1.0, 6.0, false, 0);
-
-    //Substract gradient field
-    for ii in 1..=X_SIZE {
-        for jj in 1..=Y_SIZE  {
-            for kk in 1..=Z_SIZE  {
-                let ix = IX!(ii, jj, kk);
-                let ix_up = IX!(ii, jj - 1, kk); // 1 row up
-                let ix_down = IX!(ii, jj + 1, kk); // 1 row down
-                let ix_back = IX!(ii, jj, kk - 1); // 1 row back
-                let ix_front = IX!(ii, jj, kk + 1); // 1 row front
-                vx_grid[ix] -= 0.5 * (prev_x[ix + 1] - prev_x[ix - 1]) * X_SIZE as f32;
-                vy_grid[ix] -= 0.5 * (prev_x[ix_down] - prev_x[ix_up]) * Y_SIZE as f32;
-                vz_grid[ix] -= 0.5 * (prev_x[ix_front] - prev_x[ix_back]) * Z_SIZE as f32;
+    // Solve for the pressure (in prev_x) from the divergence (in prev_y).
+    // It has zero gradient across closed faces and wraps across periodic
+    // ones; open faces hold it at zero.
+    let walls = obstacles.with_walls(size, &config.boundary);
+    walls.remove_mean(size, prev_y);
+    let stats = solve(
+        size,
+        prev_x,
+        prev_y,
+        &walls,
+        1.0,
+        6.0,
+        config.pressure_solver,
+        config,
+        false,
+        Field::Scalar,
+    );
+
+    //Substract gradient field. Pressure inside obstacles and closed walls
+    //is taken to be that of the fluid cell next to them, so there is no
+    //push into them
+    let p: &[f32] = prev_x;
+    let solid = &walls.solid;
+    let pressure = |ix: usize, n: usize| if solid[n] { p[ix] } else { p[n] };
+    parallel::for_each_slice(size, vx_grid, |kk, base, slice| {
+        for jj in 1..=size.y {
+            for ii in 1..=size.x {
+                let ix = IX!(size, ii, jj, kk);
+                if solid[ix] {
+                    continue;
+                }
+                slice[ix - base] -= 0.5 * (pressure(ix, ix + 1) - pressure(ix, ix - 1)) / size.x as f32;
             }
         }
-    }
-    if borders {
-        set_borders(vx_grid, 1);
-        set_borders(vy_grid, 2);
-        set_borders(vz_grid, 3);
+    });
+    parallel::for_each_slice(size, vy_grid, |kk, base, slice| {
+        for jj in 1..=size.y {
+            for ii in 1..=size.x {
+                let ix = IX!(size, ii, jj, kk);
+                if solid[ix] {
+                    continue;
+                }
+                let ix_up = IX!(size, ii, jj - 1, kk); // 1 row up
+                let ix_down = IX!(size, ii, jj + 1, kk); // 1 row down
+                slice[ix - base] -= 0.5 * (pressure(ix, ix_down) - pressure(ix, ix_up)) / size.y as f32;
+            }
+        }
+    });
+    parallel::for_each_slice(size, vz_grid, |kk, base, slice| {
+        for jj in 1..=size.y {
+            for ii in 1..=size.x {
+                let ix = IX!(size, ii, jj, kk);
+                if solid[ix] {
+                    continue;
+                }
+                let ix_back = IX!(size, ii, jj, kk - 1); // 1 row back
+                let ix_front = IX!(size, ii, jj, kk + 1); // 1 row front
+                slice[ix - base] -= 0.5 * (pressure(ix, ix_front) - pressure(ix, ix_back)) / size.z as f32;
+            }
+        }
+    });
+    set_borders(size, vx_grid, Field::VelX, &config.boundary, obstacles);
+    set_borders(size, vy_grid, Field::VelY, &config.boundary, obstacles);
+    set_borders(size, vz_grid, Field::VelZ, &config.boundary, obstacles);
+    stats
+}
+
+/// Adds `dt * source` to `grid`, as in GDC03's `add_source`.
+fn add_source(grid: &mut [f32], source: Option<&[f32]>, dt: f32) {
+    if let Some(source) = source {
+        for (x, s) in grid.iter_mut().zip(source.iter()) {
+            *x += dt * s;
+        }
     }
 }
 
-/// Step density
+/// Step density, or any other scalar carried by the fluid
+#[allow(clippy::too_many_arguments)]
 fn step_dens(
+    size: GridSize,
     dens_grid:  &mut Box<[f32]>,
     vx_grid:  &mut Box<[f32]>,
     vy_grid:  &mut Box<[f32]>,
     vz_grid:  &mut Box<[f32]>,
-    dt: f32,
-    diff: f32,
-    borders: bool,
+    obstacles: &Obstacles,
+    diffusion: f32,
+    dissipation: f32,
+    scheme: AdvectionScheme,
+    config: &SolverConfig,
 ) {
     // Make a copy of the dens_grid
     let prev_dens_grid = &mut dens_grid.clone();
@@ -296,33 +639,89 @@ fn step_dens(
     let (prev_dens_grid, dens_grid) = (dens_grid, prev_dens_grid);
 
     // Diffuse
-    diffuse(dens_grid, prev_dens_grid, dt, diff, borders, 0);
+    diffuse(size, dens_grid, prev_dens_grid, obstacles, diffusion, config, Field::Scalar);
 
     // Swap
     let (prev_dens_grid, dens_grid) = (dens_grid, prev_dens_grid);
 
     // Advect
-    advect(
+    advect_field(
+        size,
         dens_grid,
         prev_dens_grid,
+        [vx_grid, vy_grid, vz_grid],
+        obstacles,
+        scheme,
+        config,
+        Field::Scalar,
+    );
+
+    // Dissipate
+    if dissipation > 0.0 {
+        let keep = 1.0 / (1.0 + config.dt * dissipation);
+        for d in dens_grid.iter_mut() {
+            *d *= keep;
+        }
+    }
+}
+
+/// Adds the emitter velocities, the external forces and velocity sources,
+/// buoyancy and vorticity confinement to velocities stored at the cell
+/// centers.
+#[allow(clippy::too_many_arguments)]
+fn add_forces(
+    size: GridSize,
+    vx_grid: &mut Box<[f32]>,
+    vy_grid: &mut Box<[f32]>,
+    vz_grid: &mut Box<[f32]>,
+    emitters: &Emitters,
+    transform: &GridTransform,
+    force: Option<&ForceField>,
+    velocity_source: Option<&ForceField>,
+    dens_grid: &[f32],
+    temperature: &[f32],
+    obstacles: &Obstacles,
+    config: &SolverConfig,
+) {
+    if !emitters.is_empty() {
+        let dt = config.dt;
+        let (vx, vy, vz) = (&mut *vx_grid, &mut *vy_grid, &mut *vz_grid);
+        emitters.for_each_cell(size, transform, &obstacles.solid, |emitter, ix, weight| {
+            vx[ix] += dt * weight * emitter.velocity.x;
+            vy[ix] += dt * weight * emitter.velocity.y;
+            vz[ix] += dt * weight * emitter.velocity.z;
+        });
+        set_borders(size, vx, Field::VelX, &config.boundary, obstacles);
+        set_borders(size, vy, Field::VelY, &config.boundary, obstacles);
+        set_borders(size, vz, Field::VelZ, &config.boundary, obstacles);
+    }
+    forces::add_external_forces(
+        size,
         vx_grid,
         vy_grid,
         vz_grid,
-        dt,
-        borders,
-        0,
+        force,
+        velocity_source,
+        obstacles,
+        config,
     );
+    if config.buoyancy.is_enabled() {
+        forces::add_buoyancy(size, vx_grid, vy_grid, vz_grid, dens_grid, temperature, obstacles, config);
+    }
+    if config.vorticity > 0.0 {
+        forces::confine_vorticity(size, vx_grid, vy_grid, vz_grid, obstacles, config);
+    }
 }
 
 /// Step velocity
 fn step_vel(
+    size: GridSize,
     vx_grid: &mut Box<[f32]>,
     vy_grid: &mut Box<[f32]>,
     vz_grid: &mut Box<[f32]>,
-    dt: f32,
-    diff: f32,
-    borders: bool,
-) {
+    obstacles: &Obstacles,
+    config: &SolverConfig,
+) -> SolveStats {
     let prev_x = &mut vx_grid.clone();
     let prev_y = &mut vy_grid.clone();
     let prev_z = &mut vz_grid.clone();
@@ -333,12 +732,12 @@ fn step_vel(
     let (prev_z, vz_grid) = (vz_grid, prev_z);
 
     // Diffuse just like with density but with velocity instead
-    diffuse(vx_grid, prev_x, dt, diff, borders, 1);
-    diffuse(vy_grid, prev_y, dt, diff, borders, 2);
-    diffuse(vz_grid, prev_z, dt, diff, borders, 3);
+    diffuse(size, vx_grid, prev_x, obstacles, config.viscosity, config, Field::VelX);
+    diffuse(size, vy_grid, prev_y, obstacles, config.viscosity, config, Field::VelY);
+    diffuse(size, vz_grid, prev_z, obstacles, config.viscosity, config, Field::VelZ);
 
     // For mass conservation before advect
-    project(vx_grid, vy_grid, vz_grid, prev_x, prev_y, prev_z, borders);
+    project(size, vx_grid, vy_grid, vz_grid, prev_x, prev_y, obstacles, config);
 
     // Swap grids
     let (prev_x, vx_grid) = (vx_grid, prev_x);
@@ -346,22 +745,164 @@ fn step_vel(
     let (prev_z, vz_grid) = (vz_grid, prev_z);
 
     // Advect just like with density
-    advect(vx_grid, prev_x, prev_x, prev_y, prev_z, dt, borders, 1);
-    advect(vy_grid, prev_y, prev_x, prev_y, prev_z, dt, borders, 2);
-    advect(vz_grid, prev_z, prev_x, prev_y, prev_z, dt, borders, 2);
+    {
+        let velocity: [&[f32]; 3] = [prev_x, prev_y, prev_z];
+        let scheme = config.velocity_advection;
+        advect_field(size, vx_grid, prev_x, velocity, obstacles, scheme, config, Field::VelX);
+        advect_field(size, vy_grid, prev_y, velocity, obstacles, scheme, config, Field::VelY);
+        advect_field(size, vz_grid, prev_z, velocity, obstacles, scheme, config, Field::VelZ);
+    }
 
-    project(vx_grid, vy_grid, vz_grid, prev_x, prev_y, prev_z, borders);
+    project(size, vx_grid, vy_grid, vz_grid, prev_x, prev_y, obstacles, config)
 }
 
-pub fn step_fluid(
-    dens_grid: &mut Box<[f32]>,
+/// Step velocity stored on the cell faces
+fn step_vel_staggered(
+    size: GridSize,
     vx_grid: &mut Box<[f32]>,
     vy_grid: &mut Box<[f32]>,
     vz_grid: &mut Box<[f32]>,
-    dt: f32,
-    viscosity: f32,
-    borders: bool,
-) {
-    step_dens(dens_grid, vx_grid, vy_grid, vz_grid, dt, viscosity, borders);
-    step_vel(vx_grid, vy_grid, vz_grid, dt, viscosity, borders);
+    obstacles: &Obstacles,
+    config: &SolverConfig,
+) -> SolveStats {
+    let prev_x = &mut vx_grid.clone();
+    let prev_y = &mut vy_grid.clone();
+    let prev_z = &mut vz_grid.clone();
+
+    // Swap grids
+    let (prev_x, vx_grid) = (vx_grid, prev_x);
+    let (prev_y, vy_grid) = (vy_grid, prev_y);
+    let (prev_z, vz_grid) = (vz_grid, prev_z);
+
+    diffuse(size, vx_grid, prev_x, obstacles, config.viscosity, config, Field::FaceX);
+    diffuse(size, vy_grid, prev_y, obstacles, config.viscosity, config, Field::FaceY);
+    diffuse(size, vz_grid, prev_z, obstacles, config.viscosity, config, Field::FaceZ);
+
+    mac::project(size, [vx_grid, vy_grid, vz_grid], prev_x, prev_y, obstacles, config);
+
+    // Swap grids
+    let (prev_x, vx_grid) = (vx_grid, prev_x);
+    let (prev_y, vy_grid) = (vy_grid, prev_y);
+    let (prev_z, vz_grid) = (vz_grid, prev_z);
+
+    {
+        let velocity: [&[f32]; 3] = [prev_x, prev_y, prev_z];
+        let scheme = config.velocity_advection;
+        let advect_faces = |grid: &mut Box<[f32]>, prev: &[f32], axis: usize| {
+            advection::advect(
+                scheme,
+                grid,
+                prev,
+                config,
+                |out, from, config| mac::advect(size, out, from, axis, velocity, obstacles, config),
+                |out, prev| mac::limit(size, out, prev, axis, velocity, obstacles, config),
+            )
+        };
+        advect_faces(vx_grid, prev_x, 0);
+        advect_faces(vy_grid, prev_y, 1);
+        advect_faces(vz_grid, prev_z, 2);
+    }
+
+    mac::project(size, [vx_grid, vy_grid, vz_grid], prev_x, prev_y, obstacles, config)
+}
+
+/// Advances density, temperature, the scalar channels and velocity of
+/// `grid` by `config.dt`.
+pub fn step_fluid(grid: &mut WindGrid, config: &SolverConfig) -> StepStats {
+    let size = grid.size;
+    let config = &grid.transform.config_to_grid(size, config);
+    let obstacles = Obstacles::new(grid);
+    let layout = grid.velocity_layout;
+    let [field_x, field_y, field_z] = layout.fields();
+    if !grid.bodies.is_empty() {
+        // Cells the moving bodies just entered take on their velocity
+        set_borders(size, &mut grid.x_vel, field_x, &config.boundary, &obstacles);
+        set_borders(size, &mut grid.y_vel, field_y, &config.boundary, &obstacles);
+        set_borders(size, &mut grid.z_vel, field_z, &config.boundary, &obstacles);
+    }
+    let (diffusion, dissipation) = (config.diffusion, config.dissipation);
+    add_source(&mut grid.density, grid.density_source.as_deref(), config.dt);
+    add_source(&mut grid.temperature, grid.temperature_source.as_deref(), config.dt);
+    {
+        let dt = config.dt;
+        let (density, temperature) = (&mut grid.density, &mut grid.temperature);
+        grid.emitters.for_each_cell(size, &grid.transform, &obstacles.solid, |emitter, ix, weight| {
+            density[ix] += dt * weight * emitter.density;
+            temperature[ix] += dt * weight * emitter.temperature;
+        });
+    }
+
+    // Scalars are carried by the velocity at the cell centers
+    let mut centers = match layout {
+        VelocityLayout::Collocated => None,
+        VelocityLayout::Staggered => {
+            Some(mac::centers(size, [&grid.x_vel, &grid.y_vel, &grid.z_vel], &obstacles, config))
+        }
+    };
+    let (vx, vy, vz) = match centers {
+        Some([ref mut vx, ref mut vy, ref mut vz]) => (vx, vy, vz),
+        None => (&mut grid.x_vel, &mut grid.y_vel, &mut grid.z_vel),
+    };
+    let (density_advection, temperature_advection) = (config.density_advection, config.temperature_advection);
+    step_dens(size, &mut grid.density, vx, vy, vz, &obstacles, diffusion, dissipation, density_advection, config);
+    step_dens(size, &mut grid.temperature, vx, vy, vz, &obstacles, diffusion, 0.0, temperature_advection, config);
+    for scalar in grid.scalars.iter_mut() {
+        add_source(&mut scalar.values, scalar.source.as_deref(), config.dt);
+        step_dens(
+            size,
+            &mut scalar.values,
+            vx,
+            vy,
+            vz,
+            &obstacles,
+            grid.transform.diffusion_to_grid(scalar.diffusion),
+            scalar.dissipation,
+            scalar.advection,
+            config,
+        );
+    }
+
+    let pressure = match centers {
+        None => {
+            add_forces(
+                size,
+                &mut grid.x_vel,
+                &mut grid.y_vel,
+                &mut grid.z_vel,
+                &grid.emitters,
+                &grid.transform,
+                grid.force.as_ref(),
+                grid.velocity_source.as_ref(),
+                &grid.density,
+                &grid.temperature,
+                &obstacles,
+                config,
+            );
+            step_vel(size, &mut grid.x_vel, &mut grid.y_vel, &mut grid.z_vel, &obstacles, config)
+        }
+        Some([mut cx, mut cy, mut cz]) => {
+            // Forces act on the cell centers and their change is shared
+            // out to the faces
+            let before = [cx.clone(), cy.clone(), cz.clone()];
+            add_forces(
+                size,
+                &mut cx,
+                &mut cy,
+                &mut cz,
+                &grid.emitters,
+                &grid.transform,
+                grid.force.as_ref(),
+                grid.velocity_source.as_ref(),
+                &grid.density,
+                &grid.temperature,
+                &obstacles,
+                config,
+            );
+            mac::add_center_change(size, &mut grid.x_vel, 0, &cx, &before[0], &obstacles, config);
+            mac::add_center_change(size, &mut grid.y_vel, 1, &cy, &before[1], &obstacles, config);
+            mac::add_center_change(size, &mut grid.z_vel, 2, &cz, &before[2], &obstacles, config);
+            step_vel_staggered(size, &mut grid.x_vel, &mut grid.y_vel, &mut grid.z_vel, &obstacles, config)
+        }
+    };
+    StepStats { pressure }
 }
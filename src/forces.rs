@@ -0,0 +1,214 @@
+//! Forces added to the velocity field at the start of each velocity step.
+//!
+//! Velocities are in domain lengths per second, so the spacing between
+//! cell centers along an axis is `1 / n` for `n` cells.
+
+use boundary::{set_borders, Field};
+use config::SolverConfig;
+use obstacle::Obstacles;
+use parallel;
+use types::*;
+
+/// Adds `dt * (uniform_force + force + velocity_source)` to the velocity of
+/// every fluid cell.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn add_external_forces(
+    size: GridSize,
+    vx_grid: &mut [f32],
+    vy_grid: &mut [f32],
+    vz_grid: &mut [f32],
+    force: Option<&ForceField>,
+    velocity_source: Option<&ForceField>,
+    obstacles: &Obstacles,
+    config: &SolverConfig,
+) {
+    let solid = &obstacles.solid;
+    let dt = config.dt;
+    let uniform = config.uniform_force;
+    let components = [
+        (vx_grid, uniform.x, force.map(|f| &f.x[..]), velocity_source.map(|f| &f.x[..]), Field::VelX),
+        (vy_grid, uniform.y, force.map(|f| &f.y[..]), velocity_source.map(|f| &f.y[..]), Field::VelY),
+        (vz_grid, uniform.z, force.map(|f| &f.z[..]), velocity_source.map(|f| &f.z[..]), Field::VelZ),
+    ];
+    for (grid, uniform, field, source, component) in components {
+        if uniform == 0.0 && field.is_none() && source.is_none() {
+            continue;
+        }
+        parallel::for_each_slice(size, grid, |kk, base, slice| {
+            for jj in 1..=size.y {
+                for ii in 1..=size.x {
+                    let ix = IX!(size, ii, jj, kk);
+                    if !solid[ix] {
+                        let local = field.map_or(0.0, |f| f[ix]) + source.map_or(0.0, |s| s[ix]);
+                        slice[ix - base] += dt * (uniform + local);
+                    }
+                }
+            }
+        });
+        set_borders(size, grid, component, &config.boundary, obstacles);
+    }
+}
+
+/// Adds `dt * (lift * (temperature - ambient) - weight * density) * up` to
+/// the velocity of every fluid cell, with `up` already in grid units.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn add_buoyancy(
+    size: GridSize,
+    vx_grid: &mut [f32],
+    vy_grid: &mut [f32],
+    vz_grid: &mut [f32],
+    dens_grid: &[f32],
+    temperature: &[f32],
+    obstacles: &Obstacles,
+    config: &SolverConfig,
+) {
+    let solid = &obstacles.solid;
+    let buoyancy = config.buoyancy;
+    let dt = config.dt;
+    let up = buoyancy.up;
+    let components = [
+        (vx_grid, up[0], Field::VelX),
+        (vy_grid, up[1], Field::VelY),
+        (vz_grid, up[2], Field::VelZ),
+    ];
+    for (grid, up, component) in components {
+        if up == 0.0 {
+            continue;
+        }
+        parallel::for_each_slice(size, grid, |kk, base, slice| {
+            for jj in 1..=size.y {
+                for ii in 1..=size.x {
+                    let ix = IX!(size, ii, jj, kk);
+                    if !solid[ix] {
+                        let warmth = temperature[ix] - buoyancy.ambient_temperature;
+                        let lift = buoyancy.lift * warmth - buoyancy.weight * dens_grid[ix];
+                        slice[ix - base] += dt * lift * up;
+                    }
+                }
+            }
+        });
+        set_borders(size, grid, component, &config.boundary, obstacles);
+    }
+}
+
+/// Adds `dt * strength * h * (N x w)` to the velocity, where `w` is the
+/// curl of the velocity, `N` the unit vector pointing towards higher
+/// `|w|` and `h` the cell size. This feeds energy back into the small
+/// eddies that advection smooths out. Everything is worked out in cells,
+/// which are cubes, so each velocity component is scaled by its number of
+/// cells on the way in and back on the way out.
+pub(crate) fn confine_vorticity(
+    size: GridSize,
+    vx_grid: &mut [f32],
+    vy_grid: &mut [f32],
+    vz_grid: &mut [f32],
+    obstacles: &Obstacles,
+    config: &SolverConfig,
+) {
+    let len = size.size_1d();
+    let solid = &obstacles.solid;
+    let row = size.x + 2;
+    let slab = row * (size.y + 2);
+    let n = [size.x as f32, size.y as f32, size.z as f32];
+
+    // Curl of the velocity in cells per second, differenced across two cells
+    let mut wx = vec![0.0; len];
+    let mut wy = vec![0.0; len];
+    let mut wz = vec![0.0; len];
+    {
+        let (vx, vy, vz): (&[f32], &[f32], &[f32]) = (vx_grid, vy_grid, vz_grid);
+        parallel::for_each_slice(size, &mut wx, |kk, base, slice| {
+            for jj in 1..=size.y {
+                for ii in 1..=size.x {
+                    let ix = IX!(size, ii, jj, kk);
+                    let dvz_dy = (vz[ix + row] - vz[ix - row]) * n[2];
+                    let dvy_dz = (vy[ix + slab] - vy[ix - slab]) * n[1];
+                    slice[ix - base] = 0.5 * (dvz_dy - dvy_dz);
+                }
+            }
+        });
+        parallel::for_each_slice(size, &mut wy, |kk, base, slice| {
+            for jj in 1..=size.y {
+                for ii in 1..=size.x {
+                    let ix = IX!(size, ii, jj, kk);
+                    let dvx_dz = (vx[ix + slab] - vx[ix - slab]) * n[0];
+                    let dvz_dx = (vz[ix + 1] - vz[ix - 1]) * n[2];
+                    slice[ix - base] = 0.5 * (dvx_dz - dvz_dx);
+                }
+            }
+        });
+        parallel::for_each_slice(size, &mut wz, |kk, base, slice| {
+            for jj in 1..=size.y {
+                for ii in 1..=size.x {
+                    let ix = IX!(size, ii, jj, kk);
+                    let dvy_dx = (vy[ix + 1] - vy[ix - 1]) * n[1];
+                    let dvx_dy = (vx[ix + row] - vx[ix - row]) * n[0];
+                    slice[ix - base] = 0.5 * (dvy_dx - dvx_dy);
+                }
+            }
+        });
+    }
+
+    // Its magnitude, with the ghost cells filled like any other scalar
+    let mut magnitude = vec![0.0; len];
+    parallel::for_each_slice(size, &mut magnitude, |kk, base, slice| {
+        for jj in 1..=size.y {
+            for ii in 1..=size.x {
+                let ix = IX!(size, ii, jj, kk);
+                slice[ix - base] = (wx[ix] * wx[ix] + wy[ix] * wy[ix] + wz[ix] * wz[ix]).sqrt();
+            }
+        }
+    });
+    set_borders(size, &mut magnitude, Field::Scalar, &config.boundary, obstacles);
+
+    let scale = config.dt * config.vorticity;
+    // Unit vector up the gradient of the magnitude, or zero where it is flat
+    let normal = |ix: usize| {
+        let nx = 0.5 * (magnitude[ix + 1] - magnitude[ix - 1]);
+        let ny = 0.5 * (magnitude[ix + row] - magnitude[ix - row]);
+        let nz = 0.5 * (magnitude[ix + slab] - magnitude[ix - slab]);
+        let length = (nx * nx + ny * ny + nz * nz).sqrt();
+        if length > 1e-12 {
+            (nx / length, ny / length, nz / length)
+        } else {
+            (0.0, 0.0, 0.0)
+        }
+    };
+
+    parallel::for_each_slice(size, vx_grid, |kk, base, slice| {
+        for jj in 1..=size.y {
+            for ii in 1..=size.x {
+                let ix = IX!(size, ii, jj, kk);
+                if !solid[ix] {
+                    let (_, ny, nz) = normal(ix);
+                    slice[ix - base] += scale * (ny * wz[ix] - nz * wy[ix]) / n[0];
+                }
+            }
+        }
+    });
+    parallel::for_each_slice(size, vy_grid, |kk, base, slice| {
+        for jj in 1..=size.y {
+            for ii in 1..=size.x {
+                let ix = IX!(size, ii, jj, kk);
+                if !solid[ix] {
+                    let (nx, _, nz) = normal(ix);
+                    slice[ix - base] += scale * (nz * wx[ix] - nx * wz[ix]) / n[1];
+                }
+            }
+        }
+    });
+    parallel::for_each_slice(size, vz_grid, |kk, base, slice| {
+        for jj in 1..=size.y {
+            for ii in 1..=size.x {
+                let ix = IX!(size, ii, jj, kk);
+                if !solid[ix] {
+                    let (nx, ny, _) = normal(ix);
+                    slice[ix - base] += scale * (nx * wy[ix] - ny * wx[ix]) / n[2];
+                }
+            }
+        }
+    });
+    set_borders(size, vx_grid, Field::VelX, &config.boundary, obstacles);
+    set_borders(size, vy_grid, Field::VelY, &config.boundary, obstacles);
+    set_borders(size, vz_grid, Field::VelZ, &config.boundary, obstacles);
+}
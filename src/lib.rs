@@ -0,0 +1,57 @@
+//! Real-time 3D fluid solver based on Jos Stam's "Real-Time Fluid Dynamics
+//! for Games" (GDC03), meant to be embedded in a game.
+//!
+//! `WindGrid` holds the simulated fields and `FluidSolver` advances them
+//! according to a `SolverConfig`. Enable the `serde` feature to (de)serialize
+//! configs, and the `parallel` feature to run advection, projection and
+//! red-black relaxation on all cores. Results are identical with and without
+//! `parallel`, whatever the number of threads.
+//!
+//! `step_fluid` and `step_frame`, which splits a frame into steps short
+//! enough for the velocity, are exposed for callers that want to manage the
+//! grid themselves, and `SparseGrid` stores large, mostly empty domains in tiles
+//! that are stepped only where the fluid is active.
+
+#[cfg(feature = "parallel")]
+extern crate rayon;
+#[cfg(feature = "serde")]
+extern crate serde;
+
+/// Macro for indexing into a 1D array using 3D coordinates.
+macro_rules! IX {
+    ( $size: expr, $x: expr, $y: expr,  $z: expr ) => {{ $size.index($x as usize, $y as usize, $z as usize) }};
+}
+
+mod advection;
+mod boundary;
+mod cfl;
+mod cg;
+mod config;
+mod emitter;
+mod fluid;
+mod forces;
+mod interpolation;
+mod mac;
+mod multigrid;
+mod obstacle;
+mod parallel;
+mod scroll;
+mod solver;
+mod sparse;
+mod transform;
+mod types;
+
+pub use advection::{AdvectionScheme, Backtrace};
+pub use boundary::{Boundary, BoundaryConditions};
+pub use cfl::{step_frame, FrameStats};
+pub use config::{Buoyancy, ConfigError, LinearSolver, Preconditioner, SolverConfig, SweepOrder};
+pub use emitter::{Emitter, EmitterHandle, EmitterShape, Emitters};
+pub use fluid::{step_fluid, SolveStats, StepStats};
+pub use interpolation::Interpolation;
+pub use mac::VelocityLayout;
+pub use obstacle::{MovingBody, Shape};
+pub use scroll::Ambient;
+pub use solver::FluidSolver;
+pub use sparse::{SparseGrid, TILE};
+pub use transform::GridTransform;
+pub use types::{ForceField, GridSize, Pos, ScalarField, Vel, WindGrid};
@@ -0,0 +1,348 @@
+//! Staggered (marker-and-cell) velocity layout.
+//!
+//! Each velocity component is stored on the cell faces perpendicular to it:
+//! `x_vel` at `(i, j, k)` is the flow through the face between cells
+//! `(i, j, k)` and `(i + 1, j, k)`, so `i = 0` and `i = nx` are the outer
+//! faces of the grid. Divergence and pressure gradient then only involve
+//! adjacent values, which leaves no room for the checkerboard pressure
+//! modes of the collocated central differences.
+
+use advection::Backtrace;
+use boundary::{set_borders, Field};
+use config::SolverConfig;
+use fluid::{solve, SolveStats};
+use interpolation::{self, Interpolation};
+use obstacle::Obstacles;
+use parallel;
+use types::*;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Where `WindGrid` stores its velocity components.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum VelocityLayout {
+    /// All three components at the cell centers, as in GDC03. The
+    /// projection differences them across two cells but solves the compact
+    /// 7-point Laplacian, so it reduces the divergence without removing it,
+    /// however far the pressure solve converges.
+    #[default]
+    Collocated,
+    /// Each component on the faces perpendicular to it. The projection
+    /// leaves no more divergence than the pressure solve's residual.
+    Staggered,
+}
+
+impl VelocityLayout {
+    /// How the x, y and z components are mirrored into the ghost cells.
+    pub(crate) fn fields(self) -> [Field; 3] {
+        match self {
+            VelocityLayout::Collocated => [Field::VelX, Field::VelY, Field::VelZ],
+            VelocityLayout::Staggered => [Field::FaceX, Field::FaceY, Field::FaceZ],
+        }
+    }
+}
+
+fn strides(size: GridSize) -> [usize; 3] {
+    [1, size.x + 2, (size.x + 2) * (size.y + 2)]
+}
+
+fn coordinates(size: GridSize, ix: usize) -> [usize; 3] {
+    let row = size.x + 2;
+    let slab = row * (size.y + 2);
+    [ix % row, ix % slab / row, ix / slab]
+}
+
+/// Sets every face perpendicular to `axis` that the solver computes, the
+/// outer ones included, to `f(ix, value)`. `set_borders` afterwards
+/// restores the faces that are fixed.
+fn update_faces<F>(size: GridSize, grid: &mut [f32], axis: usize, f: F)
+where
+    F: Fn(usize, f32) -> f32 + Send + Sync,
+{
+    let first = |a: usize| if a == axis { 0 } else { 1 };
+    parallel::for_each_slice(size, grid, |kk, base, slice| {
+        for jj in first(1)..=size.y {
+            for ii in first(0)..=size.x {
+                let ix = IX!(size, ii, jj, kk);
+                slice[ix - base] = f(ix, slice[ix - base]);
+            }
+        }
+    });
+    if axis == 2 {
+        // The lower z faces are in the ghost slice
+        for jj in 1..=size.y {
+            for ii in 1..=size.x {
+                let ix = IX!(size, ii, jj, 0);
+                grid[ix] = f(ix, grid[ix]);
+            }
+        }
+    }
+}
+
+/// Lower corner of the 8 faces around cell coordinates `pos` for the
+/// component along `axis`, and the position's fraction of the way to the
+/// upper corner. Positions are clamped to the outer faces.
+fn corners(size: GridSize, axis: usize, pos: [f32; 3]) -> ([usize; 3], [f32; 3]) {
+    let n = [size.x, size.y, size.z];
+    let mut lo = [0; 3];
+    let mut frac = [0.0; 3];
+    for a in 0..3 {
+        // faces sit half a cell above the cell centers along their axis
+        let (p, min, max) = if a == axis {
+            (pos[a] - 0.5, 0.0, n[a] as f32)
+        } else {
+            (pos[a], 0.5, n[a] as f32 + 0.5)
+        };
+        let p = p.max(min).min(max);
+        let floor = p.floor().min(n[a] as f32);
+        lo[a] = floor as usize;
+        frac[a] = p - floor;
+    }
+    (lo, frac)
+}
+
+/// Trilinear interpolation of the component stored on the faces
+/// perpendicular to `axis`, at cell coordinates `pos`.
+pub(crate) fn sample(size: GridSize, grid: &[f32], axis: usize, pos: [f32; 3]) -> f32 {
+    let (lo, frac) = corners(size, axis, pos);
+    let mut sum = 0.0;
+    for corner in 0..8 {
+        let mut weight = 1.0;
+        let mut at = [0; 3];
+        for a in 0..3 {
+            let upper = corner >> a & 1 == 1;
+            at[a] = lo[a] + upper as usize;
+            weight *= if upper { frac[a] } else { 1.0 - frac[a] };
+        }
+        if weight > 0.0 {
+            sum += weight * grid[IX!(size, at[0], at[1], at[2])];
+        }
+    }
+    sum
+}
+
+/// Velocity at the cell centers, averaged from the two faces of each cell,
+/// with ghost and solid cells filled like collocated velocities.
+pub(crate) fn centers(
+    size: GridSize,
+    faces: [&[f32]; 3],
+    obstacles: &Obstacles,
+    config: &SolverConfig,
+) -> [Box<[f32]>; 3] {
+    let stride = strides(size);
+    let fields = [Field::VelX, Field::VelY, Field::VelZ];
+    let mut centers = [
+        vec![0.0; size.size_1d()].into_boxed_slice(),
+        vec![0.0; size.size_1d()].into_boxed_slice(),
+        vec![0.0; size.size_1d()].into_boxed_slice(),
+    ];
+    for (axis, center) in centers.iter_mut().enumerate() {
+        let (face, s) = (faces[axis], stride[axis]);
+        parallel::for_each_slice(size, center, |kk, base, slice| {
+            for jj in 1..=size.y {
+                for ii in 1..=size.x {
+                    let ix = IX!(size, ii, jj, kk);
+                    slice[ix - base] = 0.5 * (face[ix - s] + face[ix]);
+                }
+            }
+        });
+        set_borders(size, center, fields[axis], &config.boundary, obstacles);
+    }
+    centers
+}
+
+/// Adds to the faces perpendicular to `axis` the change `after - before` of
+/// the matching cell-centered component, averaged over the two cells each
+/// face separates.
+pub(crate) fn add_center_change(
+    size: GridSize,
+    grid: &mut [f32],
+    axis: usize,
+    after: &[f32],
+    before: &[f32],
+    obstacles: &Obstacles,
+    config: &SolverConfig,
+) {
+    let s = strides(size)[axis];
+    update_faces(size, grid, axis, |ix, v| {
+        v + 0.5 * (after[ix] - before[ix] + after[ix + s] - before[ix + s])
+    });
+    set_borders(size, grid, VelocityLayout::Staggered.fields()[axis], &config.boundary, obstacles);
+}
+
+/// Converts velocities stored at the cell centers to the faces, averaging
+/// the two cells each face separates.
+pub(crate) fn centers_to_faces(size: GridSize, grid: &mut [f32], axis: usize) {
+    let s = strides(size)[axis];
+    let centers = grid.to_vec();
+    update_faces(size, grid, axis, |ix, _| 0.5 * (centers[ix] + centers[ix + s]));
+}
+
+/// Converts velocities stored on the faces to the cell centers.
+pub(crate) fn faces_to_centers(size: GridSize, grid: &mut [f32], axis: usize) {
+    let s = strides(size)[axis];
+    let faces = grid.to_vec();
+    parallel::for_each_slice(size, grid, |kk, base, slice| {
+        for jj in 1..=size.y {
+            for ii in 1..=size.x {
+                let ix = IX!(size, ii, jj, kk);
+                slice[ix - base] = 0.5 * (faces[ix - s] + faces[ix]);
+            }
+        }
+    });
+}
+
+/// Point the fluid at face `ix` of the component along `axis` comes from,
+/// tracing the velocity back over `dt` with `backtrace`.
+fn departure(
+    size: GridSize,
+    ix: usize,
+    axis: usize,
+    velocity: [&[f32]; 3],
+    dt: f32,
+    backtrace: Backtrace,
+) -> [f32; 3] {
+    let scale = [dt * size.x as f32, dt * size.y as f32, dt * size.z as f32];
+    let c = coordinates(size, ix);
+    let mut pos = [c[0] as f32, c[1] as f32, c[2] as f32];
+    pos[axis] += 0.5;
+    let at = |p: [f32; 3]| {
+        [
+            sample(size, velocity[0], 0, p),
+            sample(size, velocity[1], 1, p),
+            sample(size, velocity[2], 2, p),
+        ]
+    };
+    let mut here = at(pos);
+    here[axis] = velocity[axis][ix];
+    let moved = backtrace.displacement(pos, here, scale, at);
+    [pos[0] - moved[0], pos[1] - moved[1], pos[2] - moved[2]]
+}
+
+/// Semi-Lagrangian advection of the component on the faces perpendicular
+/// to `axis`: each face takes the value found by tracing the velocity at
+/// its center back over `dt`.
+pub(crate) fn advect(
+    size: GridSize,
+    grid: &mut [f32],
+    prev_grid: &[f32],
+    axis: usize,
+    velocity: [&[f32]; 3],
+    obstacles: &Obstacles,
+    config: &SolverConfig,
+) {
+    let solid = &obstacles.solid;
+    let s = strides(size)[axis];
+    update_faces(size, grid, axis, |ix, v| {
+        if solid[ix] || solid[ix + s] {
+            return v;
+        }
+        let from = departure(size, ix, axis, velocity, config.dt, config.backtrace);
+        match config.interpolation {
+            Interpolation::Linear => sample(size, prev_grid, axis, from),
+            Interpolation::MonotoneCubic => {
+                let (lo, frac) = corners(size, axis, from);
+                interpolation::tricubic(size, prev_grid, lo, frac)
+            }
+        }
+    });
+    set_borders(size, grid, VelocityLayout::Staggered.fields()[axis], &config.boundary, obstacles);
+}
+
+/// Clamps every face of `grid` to the range of the faces of `prev_grid`
+/// that `advect` interpolates between for it.
+pub(crate) fn limit(
+    size: GridSize,
+    grid: &mut [f32],
+    prev_grid: &[f32],
+    axis: usize,
+    velocity: [&[f32]; 3],
+    obstacles: &Obstacles,
+    config: &SolverConfig,
+) {
+    let solid = &obstacles.solid;
+    let s = strides(size)[axis];
+    update_faces(size, grid, axis, |ix, v| {
+        if solid[ix] || solid[ix + s] {
+            return v;
+        }
+        let (lo, _) = corners(size, axis, departure(size, ix, axis, velocity, config.dt, config.backtrace));
+        let mut min = f32::INFINITY;
+        let mut max = f32::NEG_INFINITY;
+        for corner in 0..8 {
+            let at = [lo[0] + (corner & 1), lo[1] + (corner >> 1 & 1), lo[2] + (corner >> 2 & 1)];
+            let value = prev_grid[IX!(size, at[0], at[1], at[2])];
+            min = min.min(value);
+            max = max.max(value);
+        }
+        v.max(min).min(max)
+    });
+    set_borders(size, grid, VelocityLayout::Staggered.fields()[axis], &config.boundary, obstacles);
+}
+
+/// Makes the face velocities divergence free. Like the collocated
+/// projection it works in cells, so the pressure that ends up in
+/// `pressure` is in cells² per second. `div` is scratch space.
+pub(crate) fn project(
+    size: GridSize,
+    mut faces: [&mut Box<[f32]>; 3],
+    pressure: &mut Box<[f32]>,
+    div: &mut Box<[f32]>,
+    obstacles: &Obstacles,
+    config: &SolverConfig,
+) -> SolveStats {
+    let stride = strides(size);
+    let n = [size.x as f32, size.y as f32, size.z as f32];
+    {
+        let (vx, vy, vz): (&[f32], &[f32], &[f32]) = (faces[0], faces[1], faces[2]);
+        parallel::for_each_slice(size, div, |kk, base, slice| {
+            for jj in 1..=size.y {
+                for ii in 1..=size.x {
+                    let ix = IX!(size, ii, jj, kk);
+                    slice[ix - base] = -((vx[ix] - vx[ix - stride[0]]) * n[0]
+                        + (vy[ix] - vy[ix - stride[1]]) * n[1]
+                        + (vz[ix] - vz[ix - stride[2]]) * n[2]);
+                }
+            }
+        });
+    }
+    for p in pressure.iter_mut() {
+        *p = 0.0;
+    }
+
+    // Closed faces keep their velocity, so the pressure has zero gradient
+    // across them. Periodic faces wrap it around, open faces hold it at
+    // zero.
+    let walls = obstacles.with_walls(size, &config.boundary);
+    walls.remove_mean(size, div);
+    let stats = solve(
+        size,
+        pressure,
+        div,
+        &walls,
+        1.0,
+        6.0,
+        config.pressure_solver,
+        config,
+        false,
+        Field::Scalar,
+    );
+
+    let p: &[f32] = pressure;
+    let fields = VelocityLayout::Staggered.fields();
+    for (axis, grid) in faces.iter_mut().enumerate() {
+        let s = stride[axis];
+        let solid = &walls.solid;
+        update_faces(size, grid, axis, |ix, v| {
+            if solid[ix] || solid[ix + s] {
+                v
+            } else {
+                v - (p[ix + s] - p[ix]) / n[axis]
+            }
+        });
+        set_borders(size, grid, fields[axis], &config.boundary, obstacles);
+    }
+    stats
+}
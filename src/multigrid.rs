@@ -0,0 +1,313 @@
+//! Geometric multigrid for the system `linear_solver` relaxes:
+//! `denominator * x - diff_rate * (sum of the 6 neighbors) = prev`.
+//!
+//! Levels are cell-centred and use the same ghost-cell layout as the fine
+//! grid. Each level halves the axes that are at least 2 cells long, so
+//! coarse cell `I` covers fine cells `2I-1` and `2I` along those axes and
+//! cell `I` along the others; flat grids keep coarsening in their plane.
+//! The coupling along a halved axis halves with it. A coarse cell is solid
+//! only when every fine cell it covers is, ghost cells included, so closed
+//! walls keep their zero gradient on every level and periodic axes keep
+//! wrapping around. Other ghost cells of the corrections are zero.
+
+use boundary::wrap_periodic;
+use cg;
+use config::{Preconditioner, SolverConfig, SweepOrder};
+use fluid::{fluid_neighbors, relax, residual, SolveStats};
+use types::*;
+
+const PRE_SWEEPS: usize = 2;
+const POST_SWEEPS: usize = 2;
+const COARSEST_ITERATIONS: usize = 64;
+/// Levels with at most this many cells are solved directly.
+const COARSEST_CELLS: usize = 64;
+/// Factor by which the coarsest solve cuts its residual.
+const COARSEST_REDUCTION: f32 = 1e-3;
+
+struct Level {
+    size: GridSize,
+    /// Axes that were halved to get here from the level above.
+    halved: [bool; 3],
+    rates: [f32; 3],
+    denominator: f32,
+    solid: Vec<bool>,
+    x: Vec<f32>,
+    b: Vec<f32>,
+}
+
+fn coarsen(size: GridSize) -> Option<(GridSize, [bool; 3])> {
+    let n = [size.x, size.y, size.z];
+    let halved = n.map(|n| n >= 2);
+    if size.x * size.y * size.z <= COARSEST_CELLS || !halved.contains(&true) {
+        return None;
+    }
+    let [x, y, z] = [0, 1, 2].map(|a| if halved[a] { n[a].div_ceil(2) } else { n[a] });
+    Some((GridSize::new(x, y, z), halved))
+}
+
+/// Fine cells along one axis covered by coarse cell `c`, where the fine
+/// level has `fine` interior cells and the coarse one `coarse`. Ghost
+/// cells cover the fine ghost cells on the same side.
+fn covered(c: usize, fine: usize, coarse: usize, halved: bool) -> (usize, usize) {
+    if c == 0 {
+        (0, 0)
+    } else if c == coarse + 1 {
+        (fine + 1, fine + 1)
+    } else if halved {
+        (2 * c - 1, (2 * c).min(fine))
+    } else {
+        (c, c)
+    }
+}
+
+/// Builds the coarse levels below `size`. With averaging restriction and
+/// piecewise constant prolongation the Galerkin coarse operator halves the
+/// neighbor coupling along the halved axes and leaves the identity part of
+/// the operator alone.
+fn build_levels(size: GridSize, solid: &[bool], diff_rate: f32, denominator: f32) -> Vec<Level> {
+    let identity = denominator - 6.0 * diff_rate;
+    let mut levels: Vec<Level> = Vec::new();
+    let mut size = size;
+    let mut rates = [diff_rate; 3];
+    while let Some((coarse, halved)) = coarsen(size) {
+        let solid = {
+            let fine = levels.last().map_or(solid, |level| &level.solid[..]);
+            coarsen_solid(size, fine, coarse, halved)
+        };
+        size = coarse;
+        for a in 0..3 {
+            if halved[a] {
+                rates[a] *= 0.5;
+            }
+        }
+        levels.push(Level {
+            size,
+            halved,
+            rates,
+            denominator: identity + 2.0 * (rates[0] + rates[1] + rates[2]),
+            solid,
+            x: vec![0.0; size.size_1d()],
+            b: vec![0.0; size.size_1d()],
+        });
+    }
+    levels
+}
+
+/// Marks a coarse cell solid when all the fine cells it covers are solid.
+fn coarsen_solid(fine: GridSize, solid: &[bool], coarse: GridSize, halved: [bool; 3]) -> Vec<bool> {
+    let mut coarse_solid = vec![false; coarse.size_1d()];
+    for kk in 0..=coarse.z + 1 {
+        let (z0, z1) = covered(kk, fine.z, coarse.z, halved[2]);
+        for jj in 0..=coarse.y + 1 {
+            let (y0, y1) = covered(jj, fine.y, coarse.y, halved[1]);
+            for ii in 0..=coarse.x + 1 {
+                let (x0, x1) = covered(ii, fine.x, coarse.x, halved[0]);
+                let mut all_solid = true;
+                for fk in z0..=z1 {
+                    for fj in y0..=y1 {
+                        for fi in x0..=x1 {
+                            all_solid &= solid[IX!(fine, fi, fj, fk)];
+                        }
+                    }
+                }
+                coarse_solid[IX!(coarse, ii, jj, kk)] = all_solid;
+            }
+        }
+    }
+    coarse_solid
+}
+
+#[allow(clippy::too_many_arguments)]
+fn compute_residual(
+    size: GridSize,
+    x: &[f32],
+    b: &[f32],
+    solid: &[bool],
+    rates: [f32; 3],
+    denominator: f32,
+    r: &mut [f32],
+) {
+    for kk in 1..=size.z {
+        for jj in 1..=size.y {
+            for ii in 1..=size.x {
+                let ix = IX!(size, ii, jj, kk);
+                if solid[ix] {
+                    r[ix] = 0.0;
+                    continue;
+                }
+                let (neighbors, walls) = fluid_neighbors(size, x, solid, ix, rates);
+                r[ix] = b[ix] - ((denominator - walls) * x[ix] - neighbors);
+            }
+        }
+    }
+}
+
+/// Averages the fine fluid cells covered by each coarse cell.
+fn restrict(fine: GridSize, r: &[f32], solid: &[bool], coarse: &mut Level) {
+    let (size, halved) = (coarse.size, coarse.halved);
+    for kk in 1..=size.z {
+        let (z0, z1) = covered(kk, fine.z, size.z, halved[2]);
+        for jj in 1..=size.y {
+            let (y0, y1) = covered(jj, fine.y, size.y, halved[1]);
+            for ii in 1..=size.x {
+                let (x0, x1) = covered(ii, fine.x, size.x, halved[0]);
+                let mut sum = 0.0;
+                let mut count = 0;
+                for fk in z0..=z1 {
+                    for fj in y0..=y1 {
+                        for fi in x0..=x1 {
+                            let ix = IX!(fine, fi, fj, fk);
+                            if !solid[ix] {
+                                sum += r[ix];
+                                count += 1;
+                            }
+                        }
+                    }
+                }
+                coarse.b[IX!(size, ii, jj, kk)] = if count > 0 { sum / count as f32 } else { 0.0 };
+            }
+        }
+    }
+}
+
+/// Adds the coarse correction to every fine fluid cell it covers.
+fn prolong(coarse: &Level, fine: GridSize, solid: &[bool], x: &mut [f32], periodic: [bool; 3]) {
+    let halved = coarse.halved;
+    let parent = |i: usize, halved: bool| if halved { i.div_ceil(2) } else { i };
+    for kk in 1..=fine.z {
+        for jj in 1..=fine.y {
+            for ii in 1..=fine.x {
+                let ix = IX!(fine, ii, jj, kk);
+                if !solid[ix] {
+                    let (ci, cj) = (parent(ii, halved[0]), parent(jj, halved[1]));
+                    x[ix] += coarse.x[IX!(coarse.size, ci, cj, parent(kk, halved[2]))];
+                }
+            }
+        }
+    }
+    wrap_periodic(fine, x, periodic);
+}
+
+/// Solves the coarsest level with conjugate gradient until its residual
+/// has dropped by `COARSEST_REDUCTION`. Pushing further would only stir
+/// up rounding error, which a singular system such as the pressure in a
+/// closed box amplifies. A solve that ends up worse than it started is
+/// thrown away.
+#[allow(clippy::too_many_arguments)]
+fn solve_coarsest(
+    size: GridSize,
+    x: &mut [f32],
+    b: &[f32],
+    solid: &[bool],
+    rates: [f32; 3],
+    denominator: f32,
+    periodic: [bool; 3],
+) {
+    let start = residual(size, x, b, solid, rates, denominator);
+    let initial = x.to_vec();
+    let stats = cg::solve(
+        size,
+        x,
+        b,
+        solid,
+        rates,
+        denominator,
+        COARSEST_ITERATIONS,
+        COARSEST_REDUCTION * start,
+        Preconditioner::IncompleteCholesky,
+        periodic,
+    );
+    if stats.residual.is_nan() || stats.residual >= start {
+        x.copy_from_slice(&initial);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn v_cycle(
+    size: GridSize,
+    x: &mut [f32],
+    b: &[f32],
+    solid: &[bool],
+    rates: [f32; 3],
+    denominator: f32,
+    order: SweepOrder,
+    periodic: [bool; 3],
+    levels: &mut [Level],
+) {
+    let (coarse, rest) = match levels.split_first_mut() {
+        Some(split) => split,
+        None => {
+            solve_coarsest(size, x, b, solid, rates, denominator, periodic);
+            return;
+        }
+    };
+
+    for _ in 0..PRE_SWEEPS {
+        relax(size, x, b, solid, rates, denominator, order, periodic);
+    }
+
+    let mut r = vec![0.0; size.size_1d()];
+    compute_residual(size, x, b, solid, rates, denominator, &mut r);
+    restrict(size, &r, solid, coarse);
+    for e in coarse.x.iter_mut() {
+        *e = 0.0;
+    }
+    v_cycle(
+        coarse.size,
+        &mut coarse.x,
+        &coarse.b,
+        &coarse.solid,
+        coarse.rates,
+        coarse.denominator,
+        order,
+        periodic,
+        rest,
+    );
+    prolong(coarse, size, solid, x, periodic);
+
+    for _ in 0..POST_SWEEPS {
+        relax(size, x, b, solid, rates, denominator, order, periodic);
+    }
+}
+
+/// Runs at most `config.iterations` V-cycles, stopping early once the
+/// residual drops below `config.tolerance` if that is non-zero, or once a
+/// V-cycle no longer reduces it. Past that point the cycles only feed
+/// rounding error into the constant mode of a singular system. The
+/// smoother sweeps in `config.sweep_order`. Ghost cells of `grid` are held
+/// fixed, except along the `periodic` axes, where they wrap around.
+#[allow(clippy::too_many_arguments)]
+pub fn solve(
+    size: GridSize,
+    grid: &mut [f32],
+    prev_grid: &[f32],
+    solid: &[bool],
+    diff_rate: f32,
+    denominator: f32,
+    config: &SolverConfig,
+    periodic: [bool; 3],
+) -> SolveStats {
+    let (iterations, tolerance) = (config.iterations, config.tolerance);
+    let rates = [diff_rate; 3];
+    let mut levels = build_levels(size, solid, diff_rate, denominator);
+    let mut last = f32::INFINITY;
+    for iteration in 1..=iterations {
+        v_cycle(
+            size,
+            grid,
+            prev_grid,
+            solid,
+            rates,
+            denominator,
+            config.sweep_order,
+            periodic,
+            &mut levels,
+        );
+        let residual = residual(size, grid, prev_grid, solid, rates, denominator);
+        if residual < tolerance || residual >= last || iteration == iterations {
+            return SolveStats { iterations: iteration, residual };
+        }
+        last = residual;
+    }
+    SolveStats::default()
+}
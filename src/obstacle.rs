@@ -0,0 +1,203 @@
+use boundary::{Boundary, BoundaryConditions, Field};
+use transform::GridTransform;
+use types::*;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Shape of a `MovingBody`, in meters. Boxes are aligned with the grid axes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Shape {
+    Sphere { radius: f32 },
+    /// Box extending `half_extents` from the body's position.
+    Box { half_extents: [f32; 3] },
+}
+
+/// A solid that moves through the fluid, such as a player or a vehicle.
+/// Every cell whose center lies inside it is solid for the step and holds
+/// the body's velocity, so the fluid around it is pushed along.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MovingBody {
+    /// World position of the center of the body.
+    pub position: [f32; 3],
+    /// Velocity of the body in m/s.
+    pub velocity: Vel,
+    pub shape: Shape,
+}
+
+impl MovingBody {
+    /// The body in cell coordinates and grid velocity units.
+    fn to_grid(self, transform: &GridTransform, size: GridSize) -> MovingBody {
+        let h = transform.cell_size;
+        MovingBody {
+            position: transform.world_to_cell(self.position),
+            velocity: transform.velocity_to_grid(size, self.velocity),
+            shape: match self.shape {
+                Shape::Sphere { radius } => Shape::Sphere { radius: radius / h },
+                Shape::Box { half_extents } => Shape::Box {
+                    half_extents: [half_extents[0] / h, half_extents[1] / h, half_extents[2] / h],
+                },
+            },
+        }
+    }
+
+    fn contains(&self, x: f32, y: f32, z: f32) -> bool {
+        let dx = x - self.position[0];
+        let dy = y - self.position[1];
+        let dz = z - self.position[2];
+        match self.shape {
+            Shape::Sphere { radius } => dx * dx + dy * dy + dz * dz <= radius * radius,
+            Shape::Box { half_extents } => {
+                dx.abs() <= half_extents[0] && dy.abs() <= half_extents[1] && dz.abs() <= half_extents[2]
+            }
+        }
+    }
+
+    /// Range of interior cells along one axis that the body may cover.
+    fn cell_range(&self, axis: usize, n: usize) -> (usize, usize) {
+        let reach = match self.shape {
+            Shape::Sphere { radius } => radius,
+            Shape::Box { half_extents } => half_extents[axis],
+        };
+        let lo = (self.position[axis] - reach).ceil().max(1.0);
+        let hi = (self.position[axis] + reach).floor().min(n as f32);
+        (lo as usize, hi as usize)
+    }
+}
+
+/// The static obstacles of a grid together with its moving bodies, as seen
+/// by one step.
+pub(crate) struct Obstacles {
+    pub solid: Box<[bool]>,
+    /// Velocity of the solid cells per component. Empty when there are no
+    /// moving bodies, in which case every solid cell is at rest.
+    velocity: [Vec<f32>; 3],
+    /// Axes along which the linear solves wrap the ghost cells around.
+    /// Only set by `with_walls`.
+    pub periodic: [bool; 3],
+    /// Whether an open outer face holds the pressure at zero. Only set by
+    /// `with_walls`.
+    open: bool,
+}
+
+impl Obstacles {
+    pub fn new(grid: &WindGrid) -> Obstacles {
+        let size = grid.size;
+        let mut solid = grid.solid.clone();
+        let mut velocity = [Vec::new(), Vec::new(), Vec::new()];
+        if !grid.bodies.is_empty() {
+            for v in velocity.iter_mut() {
+                *v = vec![0.0; size.size_1d()];
+            }
+        }
+        for body in grid.bodies.iter() {
+            let body = body.to_grid(&grid.transform, size);
+            let (x0, x1) = body.cell_range(0, size.x);
+            let (y0, y1) = body.cell_range(1, size.y);
+            let (z0, z1) = body.cell_range(2, size.z);
+            for kk in z0..=z1 {
+                for jj in y0..=y1 {
+                    for ii in x0..=x1 {
+                        if !body.contains(ii as f32, jj as f32, kk as f32) {
+                            continue;
+                        }
+                        let ix = IX!(size, ii, jj, kk);
+                        solid[ix] = true;
+                        velocity[0][ix] = body.velocity.x;
+                        velocity[1][ix] = body.velocity.y;
+                        velocity[2][ix] = body.velocity.z;
+                    }
+                }
+            }
+        }
+        Obstacles { solid, velocity, periodic: [false; 3], open: true }
+    }
+
+    /// Value `field` takes inside the solid cell `ix`.
+    pub fn value(&self, field: Field, ix: usize) -> f32 {
+        match field.component() {
+            Some(component) => self.velocity[component].get(ix).cloned().unwrap_or(0.0),
+            None => 0.0,
+        }
+    }
+
+    /// The obstacles as the pressure solve sees them. Ghost cells behind
+    /// closed outer faces are solid, so the pressure has zero gradient
+    /// across them, and those across periodic faces wrap around to the
+    /// cells on the opposite side. Open faces hold the pressure at zero.
+    pub fn with_walls(&self, size: GridSize, bc: &BoundaryConditions) -> Obstacles {
+        let mut solid = self.solid.clone();
+        let n = [size.x, size.y, size.z];
+        let faces = [(bc.x_min, bc.x_max), (bc.y_min, bc.y_max), (bc.z_min, bc.z_max)];
+        let closed = |b: Boundary| !matches!(b, Boundary::Outflow | Boundary::Periodic);
+        let periodic = [0, 1, 2].map(|axis| faces[axis].0 == Boundary::Periodic);
+        for axis in 0..3 {
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+            let (min, max) = faces[axis];
+            for b in 1..=n[v] {
+                for a in 1..=n[u] {
+                    let mut c = [0; 3];
+                    c[u] = a;
+                    c[v] = b;
+                    let ghost_lo = IX!(size, c[0], c[1], c[2]);
+                    c[axis] = 1;
+                    let first = IX!(size, c[0], c[1], c[2]);
+                    c[axis] = n[axis];
+                    let last = IX!(size, c[0], c[1], c[2]);
+                    c[axis] += 1;
+                    let ghost_hi = IX!(size, c[0], c[1], c[2]);
+                    if periodic[axis] {
+                        solid[ghost_lo] = self.solid[last];
+                        solid[ghost_hi] = self.solid[first];
+                    }
+                    if closed(min) {
+                        solid[ghost_lo] = true;
+                    }
+                    if closed(max) {
+                        solid[ghost_hi] = true;
+                    }
+                }
+            }
+        }
+        let open = faces.iter().any(|&(min, max)| min == Boundary::Outflow || max == Boundary::Outflow);
+        Obstacles { solid, velocity: [Vec::new(), Vec::new(), Vec::new()], periodic, open }
+    }
+
+    /// Without an open face the pressure is only fixed up to a constant,
+    /// and the pressure solve only has a solution when the divergence sums
+    /// to zero over the fluid. Removes its mean from `div` so that it does.
+    pub fn remove_mean(&self, size: GridSize, div: &mut [f32]) {
+        if self.open {
+            return;
+        }
+        let mut sum = 0.0;
+        let mut count = 0;
+        for kk in 1..=size.z {
+            for jj in 1..=size.y {
+                for ii in 1..=size.x {
+                    let ix = IX!(size, ii, jj, kk);
+                    if !self.solid[ix] {
+                        sum += div[ix];
+                        count += 1;
+                    }
+                }
+            }
+        }
+        if count == 0 {
+            return;
+        }
+        let mean = sum / count as f32;
+        for kk in 1..=size.z {
+            for jj in 1..=size.y {
+                for ii in 1..=size.x {
+                    let ix = IX!(size, ii, jj, kk);
+                    if !self.solid[ix] {
+                        div[ix] -= mean;
+                    }
+                }
+            }
+        }
+    }
+}
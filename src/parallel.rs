@@ -0,0 +1,76 @@
+//! Slice-wise loops over the interior of a grid. With the `parallel`
+//! feature the z-slices are spread over the rayon thread pool; every slice
+//! only writes its own cells and reductions are combined in slice order, so
+//! results do not depend on the number of threads.
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use types::*;
+
+/// Calls `f(kk, base, slice)` for every interior z-slice of `grid`, where
+/// `slice` holds the cells `base..base + slice.len()` of `grid`.
+pub fn for_each_slice<F>(size: GridSize, grid: &mut [f32], f: F)
+where
+    F: Fn(usize, usize, &mut [f32]) + Send + Sync,
+{
+    let slab = (size.x + 2) * (size.y + 2);
+    let interior = &mut grid[slab..slab * (size.z + 1)];
+
+    #[cfg(feature = "parallel")]
+    interior
+        .par_chunks_mut(slab)
+        .enumerate()
+        .for_each(|(k, slice)| f(k + 1, (k + 1) * slab, slice));
+
+    #[cfg(not(feature = "parallel"))]
+    for (k, slice) in interior.chunks_mut(slab).enumerate() {
+        f(k + 1, (k + 1) * slab, slice);
+    }
+}
+
+/// Calls `f(kk, slice, below, above)` for every interior z-slice `kk` of
+/// `grid` with `kk % 2 == parity`, where `below` and `above` are the slices
+/// `kk - 1` and `kk + 1`. Those have the other parity, so they can be read
+/// while this parity is written in place. All slices start at a multiple of
+/// the slab size, so an index relative to one is valid in all three.
+pub fn for_each_slice_of_parity<F>(size: GridSize, grid: &mut [f32], parity: usize, f: F)
+where
+    F: Fn(usize, &mut [f32], &[f32], &[f32]) + Send + Sync,
+{
+    let slab = (size.x + 2) * (size.y + 2);
+    let mut written = Vec::new();
+    let mut read: Vec<&[f32]> = Vec::new();
+    for (kk, slice) in grid[..slab * (size.z + 2)].chunks_mut(slab).enumerate() {
+        if kk % 2 == parity && kk >= 1 && kk <= size.z {
+            written.push((kk, slice));
+            read.push(&[]);
+        } else {
+            read.push(slice);
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    written
+        .into_par_iter()
+        .for_each(|(kk, slice)| f(kk, slice, read[kk - 1], read[kk + 1]));
+
+    #[cfg(not(feature = "parallel"))]
+    for (kk, slice) in written {
+        f(kk, slice, read[kk - 1], read[kk + 1]);
+    }
+}
+
+/// Sums `f(kk)` over the interior z-slices.
+pub fn sum_slices<F>(size: GridSize, f: F) -> f32
+where
+    F: Fn(usize) -> f32 + Send + Sync,
+{
+    #[cfg(feature = "parallel")]
+    let partial: Vec<f32> = (1..=size.z).into_par_iter().map(f).collect();
+
+    #[cfg(not(feature = "parallel"))]
+    let partial: Vec<f32> = (1..=size.z).map(f).collect();
+
+    partial.iter().sum()
+}
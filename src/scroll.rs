@@ -0,0 +1,104 @@
+//! Moving the grid window through a larger world by whole cells.
+
+use types::*;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// State of the fluid outside the grid, used to fill the cells a scroll
+/// exposes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Ambient {
+    pub density: f32,
+    pub temperature: f32,
+    /// World velocity in m/s.
+    pub velocity: Vel,
+}
+
+/// Moves the interior of `field` by `-offset` cells, filling the cells that
+/// come into view with `fill`. Ghost cells are left for the next step to
+/// update.
+fn shift<T: Copy>(size: GridSize, field: &mut [T], offset: [isize; 3], fill: T) {
+    let old = field.to_vec();
+    let n = [size.x as isize, size.y as isize, size.z as isize];
+    for kk in 1..=size.z {
+        for jj in 1..=size.y {
+            for ii in 1..=size.x {
+                let from = [ii as isize + offset[0], jj as isize + offset[1], kk as isize + offset[2]];
+                let inside = (0..3).all(|axis| from[axis] >= 1 && from[axis] <= n[axis]);
+                field[IX!(size, ii, jj, kk)] = if inside {
+                    old[IX!(size, from[0], from[1], from[2])]
+                } else {
+                    fill
+                };
+            }
+        }
+    }
+}
+
+impl WindGrid {
+    /// Moves the grid window by `offset` whole cells along its axes. The
+    /// fields keep their place in the world, so their contents shift the
+    /// other way; cells that scroll into view are filled from `ambient`.
+    /// Obstacles, sources and forces painted into cells move with the world
+    /// too, while moving bodies and emitters are already in world space.
+    pub fn scroll(&mut self, offset: [isize; 3]) {
+        if offset == [0, 0, 0] {
+            return;
+        }
+        let size = self.size;
+        let ambient = self.ambient;
+        let velocity = self.transform.velocity_to_grid(size, ambient.velocity);
+
+        shift(size, &mut self.x_vel, offset, velocity.x);
+        shift(size, &mut self.y_vel, offset, velocity.y);
+        shift(size, &mut self.z_vel, offset, velocity.z);
+        shift(size, &mut self.density, offset, ambient.density);
+        shift(size, &mut self.temperature, offset, ambient.temperature);
+        shift(size, &mut self.solid, offset, false);
+        for scalar in self.scalars.iter_mut() {
+            shift(size, &mut scalar.values, offset, 0.0);
+            if let Some(source) = scalar.source.as_mut() {
+                shift(size, source, offset, 0.0);
+            }
+        }
+        if let Some(source) = self.density_source.as_mut() {
+            shift(size, source, offset, 0.0);
+        }
+        if let Some(source) = self.temperature_source.as_mut() {
+            shift(size, source, offset, 0.0);
+        }
+        for field in [self.force.as_mut(), self.velocity_source.as_mut()].iter_mut().flatten() {
+            shift(size, &mut field.x, offset, 0.0);
+            shift(size, &mut field.y, offset, 0.0);
+            shift(size, &mut field.z, offset, 0.0);
+        }
+
+        let h = self.transform.cell_size;
+        let step = self.transform.direction_to_world([
+            offset[0] as f32 * h,
+            offset[1] as f32 * h,
+            offset[2] as f32 * h,
+        ]);
+        for (origin, step) in self.transform.origin.iter_mut().zip(step.iter()) {
+            *origin += step;
+        }
+    }
+
+    /// Scrolls the grid so that the world position `pos` falls in its
+    /// central cell, or as close as whole cells allow.
+    pub fn follow(&mut self, pos: [f32; 3]) {
+        let c = self.transform.world_to_cell(pos);
+        let n = [self.size.x, self.size.y, self.size.z];
+        let mut offset = [0; 3];
+        for axis in 0..3 {
+            let center = (n[axis] + 1) as f32 / 2.0;
+            let cells = (c[axis] - center).round();
+            if cells.is_finite() {
+                offset[axis] = cells as isize;
+            }
+        }
+        self.scroll(offset);
+    }
+}
@@ -0,0 +1,68 @@
+use cfl::{step_frame, FrameStats};
+use config::{ConfigError, SolverConfig};
+use fluid::{step_fluid, StepStats};
+use types::*;
+
+/// Owns a `WindGrid` together with the parameters used to advance it.
+#[derive(Clone, Debug)]
+pub struct FluidSolver {
+    grid: WindGrid,
+    config: SolverConfig,
+    last_stats: StepStats,
+}
+
+impl FluidSolver {
+    pub fn new(grid: WindGrid, config: SolverConfig) -> Result<FluidSolver, ConfigError> {
+        config.validate()?;
+        Ok(FluidSolver {
+            grid,
+            config,
+            last_stats: StepStats::default(),
+        })
+    }
+
+    /// Advances the simulation by one time step.
+    pub fn step(&mut self) -> StepStats {
+        self.last_stats = step_fluid(&mut self.grid, &self.config);
+        self.last_stats
+    }
+
+    /// Advances the simulation by `frame_time` seconds, in as many steps
+    /// as keep the CFL number under `max_cfl`. See `step_frame`.
+    pub fn advance(&mut self, frame_time: f32) -> FrameStats {
+        let stats = step_frame(&mut self.grid, &self.config, frame_time);
+        if stats.substeps > 0 {
+            self.last_stats = stats.last_step;
+        }
+        stats
+    }
+
+    /// Diagnostics from the most recent step.
+    pub fn last_stats(&self) -> StepStats {
+        self.last_stats
+    }
+
+    pub fn config(&self) -> &SolverConfig {
+        &self.config
+    }
+
+    /// Replaces the solver parameters, keeping the old ones if `config` is
+    /// invalid.
+    pub fn set_config(&mut self, config: SolverConfig) -> Result<(), ConfigError> {
+        config.validate()?;
+        self.config = config;
+        Ok(())
+    }
+
+    pub fn grid(&self) -> &WindGrid {
+        &self.grid
+    }
+
+    pub fn grid_mut(&mut self) -> &mut WindGrid {
+        &mut self.grid
+    }
+
+    pub fn into_grid(self) -> WindGrid {
+        self.grid
+    }
+}
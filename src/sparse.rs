@@ -0,0 +1,384 @@
+//! Sparse storage for large, mostly empty domains.
+//!
+//! Density and velocity are kept in tiles of `TILE`³ cells that exist only
+//! where something is happening; a missing tile is still air. Every step the
+//! active tiles are grown by one tile so that smoke can spread, grouped into
+//! clusters of touching tiles, and each cluster is stepped with
+//! `step_fluid` on a dense window covering it. Afterwards only the tiles
+//! whose density exceeds `threshold` or whose speed exceeds
+//! `velocity_threshold` are kept.
+//!
+//! Only density and velocity are stored. There is no temperature, no
+//! passive scalars, no obstacles, and no sources or emitters; forces that
+//! need any of these, or that would act on the missing tiles too, are
+//! rejected by `step`. Faces between a window and empty tiles are open,
+//! holding the pressure at zero one tile away from the smoke, which stands
+//! for still air around it rather than the domain's own walls.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use boundary::{Boundary, BoundaryConditions};
+use config::{ConfigError, SolverConfig};
+use fluid::{step_fluid, StepStats};
+use transform::GridTransform;
+use types::*;
+
+/// Edge length of a tile in cells.
+pub const TILE: usize = 8;
+
+const TILE_CELLS: usize = TILE * TILE * TILE;
+
+type TileKey = [usize; 3];
+
+#[derive(Clone, Debug, PartialEq)]
+struct Tile {
+    density: Vec<f32>,
+    /// World velocity in m/s, per component.
+    velocity: [Vec<f32>; 3],
+}
+
+impl Tile {
+    fn new() -> Tile {
+        Tile {
+            density: vec![0.0; TILE_CELLS],
+            velocity: [vec![0.0; TILE_CELLS], vec![0.0; TILE_CELLS], vec![0.0; TILE_CELLS]],
+        }
+    }
+
+    /// Whether any cell has more density than `density` or more speed
+    /// than `speed`.
+    fn is_active(&self, density: f32, speed: f32) -> bool {
+        (0..TILE_CELLS).any(|i| {
+            let speed2 = self.velocity[0][i] * self.velocity[0][i]
+                + self.velocity[1][i] * self.velocity[1][i]
+                + self.velocity[2][i] * self.velocity[2][i];
+            self.density[i].abs() > density || speed2 > speed * speed
+        })
+    }
+}
+
+/// Tile holding the interior cell `pos`, and the cell's index in the tile.
+fn locate(pos: Pos) -> (TileKey, usize) {
+    let (x, y, z) = (pos.x - 1, pos.y - 1, pos.z - 1);
+    let key = [x / TILE, y / TILE, z / TILE];
+    (key, x % TILE + TILE * (y % TILE + TILE * (z % TILE)))
+}
+
+/// Inclusive range of tiles, per axis.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct TileBox {
+    min: TileKey,
+    max: TileKey,
+}
+
+impl TileBox {
+    fn overlaps(&self, other: &TileBox) -> bool {
+        (0..3).all(|a| self.min[a] <= other.max[a] && other.min[a] <= self.max[a])
+    }
+
+    fn union(&self, other: &TileBox) -> TileBox {
+        let mut merged = *self;
+        for a in 0..3 {
+            merged.min[a] = merged.min[a].min(other.min[a]);
+            merged.max[a] = merged.max[a].max(other.max[a]);
+        }
+        merged
+    }
+}
+
+/// A fluid domain of `size` cells whose density and velocity are stored
+/// in tiles allocated on demand. Positions and velocities follow the same
+/// conventions as `WindGrid`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SparseGrid {
+    pub size: GridSize,
+    pub transform: GridTransform,
+    /// Tiles whose density stays at or below this after a step are freed,
+    /// unless their speed exceeds `velocity_threshold`.
+    pub threshold: f32,
+    /// Speed in m/s above which a tile is kept without any density. Pushing
+    /// smoke around stirs the air well beyond it, by amounts that only fall
+    /// off with the cube of the distance, so too low a value soon keeps
+    /// every tile of the domain.
+    pub velocity_threshold: f32,
+    tiles: BTreeMap<TileKey, Tile>,
+}
+
+impl SparseGrid {
+    /// Creates an empty domain with `nx * ny * nz` cells.
+    pub fn new(nx: usize, ny: usize, nz: usize) -> SparseGrid {
+        SparseGrid {
+            size: GridSize::new(nx, ny, nz),
+            transform: GridTransform::default(),
+            threshold: 1e-4,
+            velocity_threshold: 0.05,
+            tiles: BTreeMap::new(),
+        }
+    }
+
+    fn tile_counts(&self) -> TileKey {
+        [
+            self.size.x.div_ceil(TILE),
+            self.size.y.div_ceil(TILE),
+            self.size.z.div_ceil(TILE),
+        ]
+    }
+
+    /// Number of tiles currently allocated.
+    pub fn active_tiles(&self) -> usize {
+        self.tiles.len()
+    }
+
+    fn check(&self, pos: Pos) {
+        assert!(
+            pos.x >= 1 && pos.y >= 1 && pos.z >= 1
+                && pos.x <= self.size.x && pos.y <= self.size.y && pos.z <= self.size.z,
+            "position outside the grid"
+        );
+    }
+
+    pub fn density(&self, pos: Pos) -> f32 {
+        self.check(pos);
+        let (key, i) = locate(pos);
+        self.tiles.get(&key).map_or(0.0, |tile| tile.density[i])
+    }
+
+    /// Velocity of the cell at `pos`, in m/s.
+    pub fn velocity(&self, pos: Pos) -> Vel {
+        self.check(pos);
+        let (key, i) = locate(pos);
+        match self.tiles.get(&key) {
+            Some(tile) => Vel {
+                x: tile.velocity[0][i],
+                y: tile.velocity[1][i],
+                z: tile.velocity[2][i],
+            },
+            None => Vel::default(),
+        }
+    }
+
+    /// Adds `amount` to the density at `pos` right away.
+    pub fn add_density(&mut self, pos: Pos, amount: f32) {
+        self.check(pos);
+        let (key, i) = locate(pos);
+        self.tiles.entry(key).or_insert_with(Tile::new).density[i] += amount;
+    }
+
+    /// Adds `vel` (m/s) to the velocity at `pos` right away.
+    pub fn add_velocity(&mut self, pos: Pos, vel: Vel) {
+        self.check(pos);
+        let (key, i) = locate(pos);
+        let tile = self.tiles.entry(key).or_insert_with(Tile::new);
+        tile.velocity[0][i] += vel.x;
+        tile.velocity[1][i] += vel.y;
+        tile.velocity[2][i] += vel.z;
+    }
+
+    /// Active tiles grown by one tile in every direction, grouped into
+    /// boxes that do not overlap.
+    fn windows(&self) -> Vec<TileBox> {
+        let counts = self.tile_counts();
+        let mut grown = BTreeSet::new();
+        for key in self.tiles.keys() {
+            for dz in 0..3 {
+                for dy in 0..3 {
+                    for dx in 0..3 {
+                        let n = [key[0] + dx, key[1] + dy, key[2] + dz];
+                        if (0..3).all(|a| n[a] >= 1 && n[a] <= counts[a]) {
+                            grown.insert([n[0] - 1, n[1] - 1, n[2] - 1]);
+                        }
+                    }
+                }
+            }
+        }
+
+        // Bounding box of every cluster of touching tiles
+        let mut boxes = Vec::new();
+        while let Some(&start) = grown.iter().next() {
+            grown.remove(&start);
+            let mut bounds = TileBox { min: start, max: start };
+            let mut stack = vec![start];
+            while let Some(key) = stack.pop() {
+                bounds = bounds.union(&TileBox { min: key, max: key });
+                for dz in 0..3 {
+                    for dy in 0..3 {
+                        for dx in 0..3 {
+                            if key[0] + dx < 1 || key[1] + dy < 1 || key[2] + dz < 1 {
+                                continue;
+                            }
+                            let n = [key[0] + dx - 1, key[1] + dy - 1, key[2] + dz - 1];
+                            if grown.remove(&n) {
+                                stack.push(n);
+                            }
+                        }
+                    }
+                }
+            }
+            boxes.push(bounds);
+        }
+
+        // Clusters with overlapping boxes are stepped together
+        let mut merged = true;
+        while merged {
+            merged = false;
+            'outer: for i in 0..boxes.len() {
+                for j in (i + 1)..boxes.len() {
+                    if boxes[i].overlaps(&boxes[j]) {
+                        let other = boxes.remove(j);
+                        boxes[i] = boxes[i].union(&other);
+                        merged = true;
+                        break 'outer;
+                    }
+                }
+            }
+        }
+        boxes
+    }
+
+    /// Advances every active region by `config.dt`. Each region is stepped
+    /// on its own, with open faces where it borders empty tiles and the
+    /// faces of `config.boundary` where it reaches the edge of the domain.
+    /// Returns the stats of the region whose pressure solve ended with the
+    /// largest residual.
+    ///
+    /// Fails if `config` is invalid, lifts by temperature, which isn't
+    /// stored, or has a `uniform_force`, which would only push the air in
+    /// the active tiles.
+    pub fn step(&mut self, config: &SolverConfig) -> Result<StepStats, ConfigError> {
+        config.validate()?;
+        if config.buoyancy.lift != 0.0 {
+            return Err(ConfigError::new("buoyancy.lift", "must be zero on a sparse grid"));
+        }
+        let force = config.uniform_force;
+        if force.x != 0.0 || force.y != 0.0 || force.z != 0.0 {
+            return Err(ConfigError::new("uniform_force", "must be zero on a sparse grid"));
+        }
+        let mut stats = StepStats::default();
+        for window in self.windows() {
+            let window_stats = self.step_window(window, config);
+            if window_stats.pressure.residual >= stats.pressure.residual {
+                stats = window_stats;
+            }
+        }
+        Ok(stats)
+    }
+
+    fn step_window(&mut self, window: TileBox, config: &SolverConfig) -> StepStats {
+        let n = [self.size.x, self.size.y, self.size.z];
+        // First cell of the window and its number of cells, per axis
+        let mut start = [0; 3];
+        let mut len = [0; 3];
+        for a in 0..3 {
+            start[a] = window.min[a] * TILE + 1;
+            len[a] = ((window.max[a] + 1) * TILE).min(n[a]) + 1 - start[a];
+        }
+
+        let mut grid = WindGrid::new(len[0], len[1], len[2]);
+        grid.transform = GridTransform {
+            origin: self.transform.cell_to_world([
+                start[0] as f32 - 1.0,
+                start[1] as f32 - 1.0,
+                start[2] as f32 - 1.0,
+            ]),
+            ..self.transform
+        };
+        let size = grid.size;
+
+        // Gather
+        for kk in 1..=size.z {
+            for jj in 1..=size.y {
+                for ii in 1..=size.x {
+                    let pos = Pos { x: start[0] + ii - 1, y: start[1] + jj - 1, z: start[2] + kk - 1 };
+                    let (key, i) = locate(pos);
+                    if let Some(tile) = self.tiles.get(&key) {
+                        let ix = IX!(size, ii, jj, kk);
+                        let v = Vel {
+                            x: tile.velocity[0][i],
+                            y: tile.velocity[1][i],
+                            z: tile.velocity[2][i],
+                        };
+                        let v = grid.transform.velocity_to_grid(size, v);
+                        grid.density[ix] = tile.density[i];
+                        grid.x_vel[ix] = v.x;
+                        grid.y_vel[ix] = v.y;
+                        grid.z_vel[ix] = v.z;
+                    }
+                }
+            }
+        }
+
+        let mut config = config.clone();
+        config.boundary = self.window_boundary(start, len, &config.boundary);
+        let stats = step_fluid(&mut grid, &config);
+
+        // Scatter, keeping only the tiles that are still active
+        for tz in window.min[2]..=window.max[2] {
+            for ty in window.min[1]..=window.max[1] {
+                for tx in window.min[0]..=window.max[0] {
+                    let key = [tx, ty, tz];
+                    let mut tile = Tile::new();
+                    for z in 0..TILE {
+                        for y in 0..TILE {
+                            for x in 0..TILE {
+                                let cell = [tx * TILE + x + 1, ty * TILE + y + 1, tz * TILE + z + 1];
+                                if (0..3).any(|a| cell[a] > n[a]) {
+                                    continue;
+                                }
+                                let ix = IX!(
+                                    size,
+                                    cell[0] + 1 - start[0],
+                                    cell[1] + 1 - start[1],
+                                    cell[2] + 1 - start[2]
+                                );
+                                let i = x + TILE * (y + TILE * z);
+                                let v = Vel { x: grid.x_vel[ix], y: grid.y_vel[ix], z: grid.z_vel[ix] };
+                                let v = grid.transform.velocity_to_world(size, v);
+                                tile.density[i] = grid.density[ix];
+                                tile.velocity[0][i] = v.x;
+                                tile.velocity[1][i] = v.y;
+                                tile.velocity[2][i] = v.z;
+                            }
+                        }
+                    }
+                    if tile.is_active(self.threshold, self.velocity_threshold) {
+                        self.tiles.insert(key, tile);
+                    } else {
+                        self.tiles.remove(&key);
+                    }
+                }
+            }
+        }
+        stats
+    }
+
+    /// Faces of a window: those on the edge of the domain keep the
+    /// domain's condition, the others are open.
+    fn window_boundary(&self, start: [usize; 3], len: [usize; 3], bc: &BoundaryConditions) -> BoundaryConditions {
+        let n = [self.size.x, self.size.y, self.size.z];
+        let faces = [(bc.x_min, bc.x_max), (bc.y_min, bc.y_max), (bc.z_min, bc.z_max)];
+        let mut out = [(Boundary::Outflow, Boundary::Outflow); 3];
+        for a in 0..3 {
+            let at_min = start[a] == 1;
+            let at_max = start[a] + len[a] - 1 == n[a];
+            let (min, max) = faces[a];
+            if min == Boundary::Periodic && !(at_min && at_max) {
+                // Wrapping needs the whole axis in one window
+                continue;
+            }
+            if at_min {
+                out[a].0 = min;
+            }
+            if at_max {
+                out[a].1 = max;
+            }
+        }
+        BoundaryConditions {
+            x_min: out[0].0,
+            x_max: out[0].1,
+            y_min: out[1].0,
+            y_max: out[1].1,
+            z_min: out[2].0,
+            z_max: out[2].1,
+        }
+    }
+}
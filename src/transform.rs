@@ -0,0 +1,156 @@
+use boundary::Boundary;
+use config::SolverConfig;
+use types::*;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Places a grid in the world. Cell coordinates have the cell centers at
+/// integers, so the interior spans `0.5..n + 0.5` along each axis.
+///
+/// Internally the solver measures velocities in grid lengths per second
+/// along each axis; the conversions below map them to and from world units.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct GridTransform {
+    /// World position of cell coordinate `(0, 0, 0)`, the center of the
+    /// corner ghost cell.
+    pub origin: [f32; 3],
+    /// Edge length of a cell in meters.
+    pub cell_size: f32,
+    /// Orientation of the grid axes in the world as a unit quaternion
+    /// `[x, y, z, w]`.
+    pub rotation: [f32; 4],
+}
+
+impl Default for GridTransform {
+    /// World coordinates equal cell coordinates, with one meter cells.
+    fn default() -> GridTransform {
+        GridTransform {
+            origin: [0.0; 3],
+            cell_size: 1.0,
+            rotation: [0.0, 0.0, 0.0, 1.0],
+        }
+    }
+}
+
+/// Rotates `v` by the unit quaternion `q`.
+fn rotate(q: [f32; 4], v: [f32; 3]) -> [f32; 3] {
+    let (x, y, z, w) = (q[0], q[1], q[2], q[3]);
+    // t = 2 * cross(q.xyz, v)
+    let t = [
+        2.0 * (y * v[2] - z * v[1]),
+        2.0 * (z * v[0] - x * v[2]),
+        2.0 * (x * v[1] - y * v[0]),
+    ];
+    [
+        v[0] + w * t[0] + (y * t[2] - z * t[1]),
+        v[1] + w * t[1] + (z * t[0] - x * t[2]),
+        v[2] + w * t[2] + (x * t[1] - y * t[0]),
+    ]
+}
+
+impl GridTransform {
+    pub fn new(origin: [f32; 3], cell_size: f32, rotation: [f32; 4]) -> GridTransform {
+        assert!(cell_size.is_finite() && cell_size > 0.0, "cell size must be positive");
+        let length = rotation.iter().map(|c| c * c).sum::<f32>().sqrt();
+        assert!(length.is_finite() && length > 0.0, "rotation must be a non-zero quaternion");
+        let rotation = [
+            rotation[0] / length,
+            rotation[1] / length,
+            rotation[2] / length,
+            rotation[3] / length,
+        ];
+        GridTransform { origin, cell_size, rotation }
+    }
+
+    fn inverse_rotation(&self) -> [f32; 4] {
+        let q = self.rotation;
+        [-q[0], -q[1], -q[2], q[3]]
+    }
+
+    /// Rotates a world direction onto the grid axes.
+    pub fn direction_to_grid(&self, d: [f32; 3]) -> [f32; 3] {
+        rotate(self.inverse_rotation(), d)
+    }
+
+    /// Rotates a direction along the grid axes into the world.
+    pub fn direction_to_world(&self, d: [f32; 3]) -> [f32; 3] {
+        rotate(self.rotation, d)
+    }
+
+    pub fn world_to_cell(&self, p: [f32; 3]) -> [f32; 3] {
+        let o = self.origin;
+        let d = self.direction_to_grid([p[0] - o[0], p[1] - o[1], p[2] - o[2]]);
+        let h = self.cell_size;
+        [d[0] / h, d[1] / h, d[2] / h]
+    }
+
+    pub fn cell_to_world(&self, c: [f32; 3]) -> [f32; 3] {
+        let h = self.cell_size;
+        let d = self.direction_to_world([c[0] * h, c[1] * h, c[2] * h]);
+        let o = self.origin;
+        [o[0] + d[0], o[1] + d[1], o[2] + d[2]]
+    }
+
+    /// Converts a world velocity in m/s, or an acceleration in m/s², to the
+    /// units the solver uses on a grid of `size` cells.
+    pub fn velocity_to_grid(&self, size: GridSize, v: Vel) -> Vel {
+        let d = self.direction_to_grid([v.x, v.y, v.z]);
+        let h = self.cell_size;
+        Vel {
+            x: d[0] / (size.x as f32 * h),
+            y: d[1] / (size.y as f32 * h),
+            z: d[2] / (size.z as f32 * h),
+        }
+    }
+
+    /// Inverse of `velocity_to_grid`.
+    pub fn velocity_to_world(&self, size: GridSize, v: Vel) -> Vel {
+        let h = self.cell_size;
+        let d = self.direction_to_world([
+            v.x * size.x as f32 * h,
+            v.y * size.y as f32 * h,
+            v.z * size.z as f32 * h,
+        ]);
+        Vel { x: d[0], y: d[1], z: d[2] }
+    }
+
+    /// Converts a diffusion coefficient or kinematic viscosity in m²/s to
+    /// cells² per second.
+    pub fn diffusion_to_grid(&self, d: f32) -> f32 {
+        d / (self.cell_size * self.cell_size)
+    }
+
+    /// Copy of `config` with its world-space quantities converted to grid
+    /// units: inflow velocities, the uniform force, the diffusion rates and
+    /// the buoyancy direction, scaled so that it converts the buoyancy
+    /// accelerations.
+    pub(crate) fn config_to_grid(&self, size: GridSize, config: &SolverConfig) -> SolverConfig {
+        let mut config = config.clone();
+        {
+            let faces = [
+                &mut config.boundary.x_min,
+                &mut config.boundary.x_max,
+                &mut config.boundary.y_min,
+                &mut config.boundary.y_max,
+                &mut config.boundary.z_min,
+                &mut config.boundary.z_max,
+            ];
+            for face in faces {
+                if let Boundary::Inflow(v) = *face {
+                    *face = Boundary::Inflow(self.velocity_to_grid(size, v));
+                }
+            }
+        }
+        config.uniform_force = self.velocity_to_grid(size, config.uniform_force);
+        config.diffusion = self.diffusion_to_grid(config.diffusion);
+        config.viscosity = self.diffusion_to_grid(config.viscosity);
+        let up = config.buoyancy.up;
+        let length = (up[0] * up[0] + up[1] * up[1] + up[2] * up[2]).sqrt();
+        let up = Vel { x: up[0] / length, y: up[1] / length, z: up[2] / length };
+        let up = self.velocity_to_grid(size, up);
+        config.buoyancy.up = [up.x, up.y, up.z];
+        config
+    }
+}
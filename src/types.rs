@@ -1,61 +1,520 @@
 #![allow(dead_code)]
-pub const X_SIZE: usize = 96;
-pub const Y_SIZE: usize = 96;
-pub const Z_SIZE: usize = 4;
-pub const SIZE_1D: usize = (X_SIZE+2)*(Y_SIZE+2)*(Z_SIZE+2);
 
+use advection::AdvectionScheme;
+use emitter::{Emitter, EmitterHandle, Emitters};
+use mac::{self, VelocityLayout};
+use obstacle::MovingBody;
+use scroll::Ambient;
+use transform::GridTransform;
 
-#[derive(Clone, Copy, Default)]
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Number of interior cells along each axis. Every field is stored with one
+/// ghost cell on each side, so the backing arrays are `(x+2)*(y+2)*(z+2)` long.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GridSize {
+    pub x: usize,
+    pub y: usize,
+    pub z: usize,
+}
+
+impl GridSize {
+    pub fn new(x: usize, y: usize, z: usize) -> GridSize {
+        assert!(x > 0 && y > 0 && z > 0, "grid dimensions must be non-zero");
+        GridSize { x, y, z }
+    }
+
+    /// Length of a field including the ghost cells.
+    pub fn size_1d(&self) -> usize {
+        (self.x + 2) * (self.y + 2) * (self.z + 2)
+    }
+
+    pub fn index(&self, x: usize, y: usize, z: usize) -> usize {
+        x + (self.x + 2) * (y + (self.y + 2) * z)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Vel {
     pub x: f32,
     pub y: f32,
     pub z: f32,
 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Pos {
     pub x: usize,
     pub y: usize,
     pub z: usize,
 }
 
+/// Acceleration per cell, stored like the velocity components in the
+/// solver's grid units. `WindGrid::add_force` and
+/// `WindGrid::add_velocity_source` take m/s² instead.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ForceField {
+    pub x: Box<[f32]>,
+    pub y: Box<[f32]>,
+    pub z: Box<[f32]>,
+}
+
+/// A named scalar quantity carried by the fluid, such as smoke, fog or dust.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScalarField {
+    pub name: String,
+    pub values: Box<[f32]>,
+    /// Diffusion coefficient in m²/s, like `SolverConfig::diffusion` for
+    /// `density`.
+    pub diffusion: f32,
+    /// Fraction lost per second, like `SolverConfig::dissipation`.
+    pub dissipation: f32,
+    /// Amount added per second, see `WindGrid::add_scalar_source`.
+    pub source: Option<Box<[f32]>>,
+    pub advection: AdvectionScheme,
+}
+
+/// Adds `rate` to cell `index` of a source field, allocating it on first use.
+fn add_rate(source: &mut Option<Box<[f32]>>, len: usize, index: usize, rate: f32) {
+    source.get_or_insert_with(|| vec![0_f32; len].into_boxed_slice())[index] += rate;
+}
+
+/// Adds `rate` to cell `index` of a per-cell vector field, allocating it on
+/// first use.
+fn add_vector_rate(field: &mut Option<ForceField>, len: usize, index: usize, rate: Vel) {
+    let field = field.get_or_insert_with(|| ForceField {
+        x: vec![0_f32; len].into_boxed_slice(),
+        y: vec![0_f32; len].into_boxed_slice(),
+        z: vec![0_f32; len].into_boxed_slice(),
+    });
+    field.x[index] += rate.x;
+    field.y[index] += rate.y;
+    field.z[index] += rate.z;
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct WindGrid {
+    pub size: GridSize,
+    /// Where the grid sits in the world. Positions and velocities passed to
+    /// or returned by the methods below are in world units (meters, m/s)
+    /// unless they are `Pos` cell indices. The raw fields use grid units.
+    pub transform: GridTransform,
+    /// Fills the cells that come into view when the grid scrolls.
+    pub ambient: Ambient,
+    /// Whether `x_vel`, `y_vel` and `z_vel` hold cell-center or face values.
+    /// Change it with `set_velocity_layout` to convert the stored velocity.
+    pub velocity_layout: VelocityLayout,
     pub x_vel: Box<[f32]>,
     pub y_vel: Box<[f32]>,
     pub z_vel: Box<[f32]>,
     pub density: Box<[f32]>,
+    /// Carried and diffused like `density`. Drives the buoyancy force.
+    pub temperature: Box<[f32]>,
+    /// Density added per second by `step_fluid`, until cleared.
+    pub density_source: Option<Box<[f32]>>,
+    /// Temperature added per second by `step_fluid`, until cleared.
+    pub temperature_source: Option<Box<[f32]>>,
+    /// Velocity added per second by `step_fluid`, in grid units, until
+    /// cleared by `clear_sources`.
+    pub velocity_source: Option<ForceField>,
+    /// Extra scalar channels, stepped after `density` with their own rates.
+    pub scalars: Vec<ScalarField>,
+    /// Per-cell forces integrated into the velocity every step until
+    /// cleared. `None` until the first `add_force`.
+    pub force: Option<ForceField>,
+    /// Shaped sources, applied on every step while enabled.
+    pub emitters: Emitters,
+    /// Cells occupied by obstacles. Fluid flows around them and neither
+    /// velocity nor density enters them. Ghost cells are never solid.
+    pub solid: Box<[bool]>,
+    /// Moving solids, rasterized into the obstacle mask at the start of
+    /// every step. Update their positions and velocities between steps.
+    pub bodies: Vec<MovingBody>,
 }
 
 impl WindGrid {
-    pub fn add_velocity_source(&mut self, pos: Pos, vel: Vel) {
-        let p_x = pos.x as usize;
-        let p_y = pos.y as usize;
-        let p_z = pos.z as usize;
+    /// Creates an empty grid with `nx * ny * nz` interior cells.
+    pub fn new(nx: usize, ny: usize, nz: usize) -> WindGrid {
+        let size = GridSize::new(nx, ny, nz);
+        let len = size.size_1d();
+        WindGrid {
+            size,
+            transform: GridTransform::default(),
+            ambient: Ambient::default(),
+            velocity_layout: VelocityLayout::default(),
+            x_vel: vec![0_f32; len].into_boxed_slice(),
+            y_vel: vec![0_f32; len].into_boxed_slice(),
+            z_vel: vec![0_f32; len].into_boxed_slice(),
+            density: vec![0_f32; len].into_boxed_slice(),
+            temperature: vec![0_f32; len].into_boxed_slice(),
+            density_source: None,
+            temperature_source: None,
+            velocity_source: None,
+            scalars: Vec::new(),
+            force: None,
+            emitters: Emitters::default(),
+            solid: vec![false; len].into_boxed_slice(),
+            bodies: Vec::new(),
+        }
+    }
 
-        let index = Self::get_index(p_x, p_y, p_z);
+    /// Adds `vel` (m/s) per second to the velocity at `pos` on every step
+    /// until `clear_sources`. Repeated calls accumulate.
+    pub fn add_velocity_source(&mut self, pos: Pos, vel: Vel) {
+        let vel = self.transform.velocity_to_grid(self.size, vel);
+        let len = self.size.size_1d();
+        let index = self.get_index(pos.x, pos.y, pos.z);
+        add_vector_rate(&mut self.velocity_source, len, index, vel);
+    }
 
-        self.x_vel[index] = vel.x;
-        self.y_vel[index] = vel.y;
-        self.z_vel[index] = vel.z;
+    /// `add_velocity_source` at the cell containing the world position
+    /// `pos`. Positions outside the grid are ignored.
+    pub fn add_velocity_source_at(&mut self, pos: [f32; 3], vel: Vel) {
+        if let Some(cell) = self.cell_at(pos) {
+            self.add_velocity_source(cell, vel);
+        }
     }
+
+    /// Adds `dens` per second to the density at `pos` on every step until
+    /// `clear_sources`. Repeated calls accumulate.
     pub fn add_density_source(&mut self, pos: Pos, dens: f32) {
-        let index = Self::get_index(pos.x as usize, pos.y as usize, pos.z as usize);
-        self.density[index] = dens;
+        let len = self.size.size_1d();
+        let index = self.get_index(pos.x, pos.y, pos.z);
+        add_rate(&mut self.density_source, len, index, dens);
+    }
+
+    /// `add_density_source` at the cell containing the world position
+    /// `pos`. Positions outside the grid are ignored.
+    pub fn add_density_source_at(&mut self, pos: [f32; 3], dens: f32) {
+        if let Some(cell) = self.cell_at(pos) {
+            self.add_density_source(cell, dens);
+        }
+    }
+
+    /// Removes every density, temperature, scalar and velocity source.
+    /// Per-cell forces stay until `clear_forces`.
+    pub fn clear_sources(&mut self) {
+        self.density_source = None;
+        self.temperature_source = None;
+        self.velocity_source = None;
+        for scalar in self.scalars.iter_mut() {
+            scalar.source = None;
+        }
     }
 
-    pub fn get_index(x: usize, y: usize, z: usize) -> usize {
-        x + (X_SIZE + 2) * (y + (Y_SIZE + 2) * z)
+    /// Marks the interior cell at `pos` as solid or fluid. Positions outside
+    /// the interior are ignored.
+    pub fn set_solid(&mut self, pos: Pos, solid: bool) {
+        if pos.x < 1 || pos.y < 1 || pos.z < 1 {
+            return;
+        }
+        if pos.x > self.size.x || pos.y > self.size.y || pos.z > self.size.z {
+            return;
+        }
+        let index = self.get_index(pos.x, pos.y, pos.z);
+        self.solid[index] = solid;
     }
 
+    /// Marks the interior cell containing the world position `pos` as solid
+    /// or fluid. Positions outside the grid are ignored.
+    pub fn set_solid_at(&mut self, pos: [f32; 3], solid: bool) {
+        if let Some(cell) = self.cell_at(pos) {
+            self.set_solid(cell, solid);
+        }
+    }
+
+    pub fn is_solid(&self, pos: Pos) -> bool {
+        let index = self.get_index(pos.x, pos.y, pos.z);
+        self.solid[index]
+    }
+
+    /// Marks every interior cell between `min` and `max` (inclusive) solid.
+    pub fn add_solid_box(&mut self, min: Pos, max: Pos) {
+        let x0 = min.x.max(1);
+        let y0 = min.y.max(1);
+        let z0 = min.z.max(1);
+        let x1 = max.x.min(self.size.x);
+        let y1 = max.y.min(self.size.y);
+        let z1 = max.z.min(self.size.z);
+        for kk in z0..=z1 {
+            for jj in y0..=y1 {
+                for ii in x0..=x1 {
+                    let index = self.get_index(ii, jj, kk);
+                    self.solid[index] = true;
+                }
+            }
+        }
+    }
+
+    /// Marks every interior cell whose center lies inside the box extending
+    /// `half_extents` meters from the world position `center` solid. Like
+    /// `Shape::Box`, the box is aligned with the grid axes.
+    pub fn add_solid_box_at(&mut self, center: [f32; 3], half_extents: [f32; 3]) {
+        let center = self.transform.world_to_cell(center);
+        let h = self.transform.cell_size;
+        let n = [self.size.x, self.size.y, self.size.z];
+        let mut range = [(0, 0); 3];
+        for axis in 0..3 {
+            let reach = half_extents[axis] / h;
+            let lo = (center[axis] - reach).ceil().max(1.0);
+            let hi = (center[axis] + reach).floor().min(n[axis] as f32);
+            if lo > hi {
+                return;
+            }
+            range[axis] = (lo as usize, hi as usize);
+        }
+        let min = Pos { x: range[0].0, y: range[1].0, z: range[2].0 };
+        let max = Pos { x: range[0].1, y: range[1].1, z: range[2].1 };
+        self.add_solid_box(min, max);
+    }
+
+    /// Marks every interior cell whose center lies within `radius` meters
+    /// of the world position `center` solid.
+    pub fn add_solid_sphere(&mut self, center: [f32; 3], radius: f32) {
+        let center = self.transform.world_to_cell(center);
+        let radius = radius / self.transform.cell_size;
+        for kk in 1..=self.size.z {
+            for jj in 1..=self.size.y {
+                for ii in 1..=self.size.x {
+                    let dx = ii as f32 - center[0];
+                    let dy = jj as f32 - center[1];
+                    let dz = kk as f32 - center[2];
+                    if dx * dx + dy * dy + dz * dz <= radius * radius {
+                        let index = self.get_index(ii, jj, kk);
+                        self.solid[index] = true;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Removes all obstacles.
+    pub fn clear_solids(&mut self) {
+        for s in self.solid.iter_mut() {
+            *s = false;
+        }
+    }
+
+    /// Registers a moving body and returns its index in `bodies`.
+    pub fn add_moving_body(&mut self, body: MovingBody) -> usize {
+        self.bodies.push(body);
+        self.bodies.len() - 1
+    }
+
+    pub fn clear_moving_bodies(&mut self) {
+        self.bodies.clear();
+    }
+
+    /// Adds `temperature` per second at `pos` on every step until
+    /// `clear_sources`.
+    pub fn add_temperature_source(&mut self, pos: Pos, temperature: f32) {
+        let len = self.size.size_1d();
+        let index = self.get_index(pos.x, pos.y, pos.z);
+        add_rate(&mut self.temperature_source, len, index, temperature);
+    }
+
+    /// `add_temperature_source` at the cell containing the world position
+    /// `pos`. Positions outside the grid are ignored.
+    pub fn add_temperature_source_at(&mut self, pos: [f32; 3], temperature: f32) {
+        if let Some(cell) = self.cell_at(pos) {
+            self.add_temperature_source(cell, temperature);
+        }
+    }
+
+    /// Adds an empty scalar channel and returns its index in `scalars`.
+    pub fn add_scalar(&mut self, name: &str, diffusion: f32, dissipation: f32) -> usize {
+        assert!(self.scalar(name).is_none(), "scalar channel names must be unique");
+        assert!(
+            diffusion.is_finite() && diffusion >= 0.0 && dissipation.is_finite() && dissipation >= 0.0,
+            "scalar diffusion and dissipation must be non-negative finite numbers"
+        );
+        self.scalars.push(ScalarField {
+            name: name.to_string(),
+            values: vec![0_f32; self.size.size_1d()].into_boxed_slice(),
+            diffusion,
+            dissipation,
+            source: None,
+            advection: AdvectionScheme::SemiLagrangian,
+        });
+        self.scalars.len() - 1
+    }
+
+    pub fn scalar(&self, name: &str) -> Option<&ScalarField> {
+        self.scalars.iter().find(|s| s.name == name)
+    }
+
+    pub fn scalar_mut(&mut self, name: &str) -> Option<&mut ScalarField> {
+        self.scalars.iter_mut().find(|s| s.name == name)
+    }
+
+    /// Adds `value` per second to scalar channel `channel` at `pos` on
+    /// every step until `clear_sources`.
+    pub fn add_scalar_source(&mut self, channel: usize, pos: Pos, value: f32) {
+        let len = self.size.size_1d();
+        let index = self.get_index(pos.x, pos.y, pos.z);
+        add_rate(&mut self.scalars[channel].source, len, index, value);
+    }
+
+    /// `add_scalar_source` at the cell containing the world position `pos`.
+    /// Positions outside the grid are ignored.
+    pub fn add_scalar_source_at(&mut self, channel: usize, pos: [f32; 3], value: f32) {
+        if let Some(cell) = self.cell_at(pos) {
+            self.add_scalar_source(channel, cell, value);
+        }
+    }
+
+    /// Adds the acceleration `force` (m/s²) to the per-cell force at `pos`.
+    pub fn add_force(&mut self, pos: Pos, force: Vel) {
+        let force = self.transform.velocity_to_grid(self.size, force);
+        let len = self.size.size_1d();
+        let index = self.get_index(pos.x, pos.y, pos.z);
+        add_vector_rate(&mut self.force, len, index, force);
+    }
+
+    /// `add_force` at the cell containing the world position `pos`.
+    /// Positions outside the grid are ignored.
+    pub fn add_force_at(&mut self, pos: [f32; 3], force: Vel) {
+        if let Some(cell) = self.cell_at(pos) {
+            self.add_force(cell, force);
+        }
+    }
+
+    /// Removes all per-cell forces. Velocity sources stay until
+    /// `clear_sources`.
+    pub fn clear_forces(&mut self) {
+        self.force = None;
+    }
+
+    pub fn add_emitter(&mut self, emitter: Emitter) -> EmitterHandle {
+        self.emitters.add(emitter)
+    }
+
+    /// The emitter behind `handle`, to move, retune or disable it. `None` once
+    /// it has been removed.
+    pub fn emitter_mut(&mut self, handle: EmitterHandle) -> Option<&mut Emitter> {
+        self.emitters.get_mut(handle)
+    }
+
+    pub fn remove_emitter(&mut self, handle: EmitterHandle) -> Option<Emitter> {
+        self.emitters.remove(handle)
+    }
+
+    pub fn get_index(&self, x: usize, y: usize, z: usize) -> usize {
+        self.size.index(x, y, z)
+    }
+
+    /// Switches between cell-centered and face-centered velocities,
+    /// interpolating the current velocity to its new place. The ghost cells
+    /// are refreshed by the next step.
+    pub fn set_velocity_layout(&mut self, layout: VelocityLayout) {
+        if layout == self.velocity_layout {
+            return;
+        }
+        let convert = match layout {
+            VelocityLayout::Collocated => mac::faces_to_centers,
+            VelocityLayout::Staggered => mac::centers_to_faces,
+        };
+        convert(self.size, &mut self.x_vel, 0);
+        convert(self.size, &mut self.y_vel, 1);
+        convert(self.size, &mut self.z_vel, 2);
+        self.velocity_layout = layout;
+    }
+
+    /// Velocity of the cell at `pos`, in m/s. On a staggered grid this is
+    /// the average of the cell's faces.
     pub fn get_velocity(&self, pos: Pos) -> Vel {
-        let p_x = pos.x as usize;
-        let p_y = pos.y as usize;
-        let p_z = pos.z as usize;
+        let index = self.get_index(pos.x, pos.y, pos.z);
+        let (x, y, z) = match self.velocity_layout {
+            VelocityLayout::Collocated => (self.x_vel[index], self.y_vel[index], self.z_vel[index]),
+            VelocityLayout::Staggered => {
+                let (row, slab) = (self.size.x + 2, (self.size.x + 2) * (self.size.y + 2));
+                (
+                    0.5 * (self.x_vel[index - 1] + self.x_vel[index]),
+                    0.5 * (self.y_vel[index - row] + self.y_vel[index]),
+                    0.5 * (self.z_vel[index - slab] + self.z_vel[index]),
+                )
+            }
+        };
+
+        self.transform.velocity_to_world(self.size, Vel { x, y, z })
+    }
+
+    /// Interior cell containing the world position `pos`, if any.
+    pub fn cell_at(&self, pos: [f32; 3]) -> Option<Pos> {
+        let c = self.transform.world_to_cell(pos);
+        let n = [self.size.x, self.size.y, self.size.z];
+        let mut cell = [0; 3];
+        for axis in 0..3 {
+            let index = c[axis].round();
+            if index.is_nan() || index < 1.0 || index > n[axis] as f32 {
+                return None;
+            }
+            cell[axis] = index as usize;
+        }
+        Some(Pos { x: cell[0], y: cell[1], z: cell[2] })
+    }
+
+    /// Velocity in m/s at the world position `pos`, interpolated trilinearly
+    /// between cell centers, or between faces on a staggered grid. Outside
+    /// the grid the value at the nearest point of the boundary is returned.
+    pub fn sample_velocity(&self, pos: [f32; 3]) -> Vel {
+        let c = self.transform.world_to_cell(pos);
+        let v = match self.velocity_layout {
+            VelocityLayout::Collocated => Vel {
+                x: self.sample(&self.x_vel, c),
+                y: self.sample(&self.y_vel, c),
+                z: self.sample(&self.z_vel, c),
+            },
+            VelocityLayout::Staggered => Vel {
+                x: mac::sample(self.size, &self.x_vel, 0, c),
+                y: mac::sample(self.size, &self.y_vel, 1, c),
+                z: mac::sample(self.size, &self.z_vel, 2, c),
+            },
+        };
+        self.transform.velocity_to_world(self.size, v)
+    }
+
+    /// Density at the world position `pos`, interpolated like
+    /// `sample_velocity`.
+    pub fn sample_density(&self, pos: [f32; 3]) -> f32 {
+        self.sample(&self.density, self.transform.world_to_cell(pos))
+    }
 
-        let index = Self::get_index(p_x, p_y, p_z);
-        let x = self.x_vel[index] as f32;
-        let y = self.y_vel[index] as f32;
-        let z = self.y_vel[index] as f32;
+    /// Trilinear interpolation of `field` at cell coordinates `pos`, leaving
+    /// out solid cells.
+    fn sample(&self, field: &[f32], pos: [f32; 3]) -> f32 {
+        let n = [self.size.x, self.size.y, self.size.z];
+        let mut lo = [0; 3];
+        let mut frac = [0.0; 3];
+        for axis in 0..3 {
+            // clamp to the outer faces, halfway between ghost and interior cells
+            let p = pos[axis].max(0.5).min(n[axis] as f32 + 0.5);
+            let floor = p.floor().min(n[axis] as f32);
+            lo[axis] = floor as usize;
+            frac[axis] = p - floor;
+        }
 
-        Vel { x, y, z }
+        let mut sum = 0.0;
+        let mut total = 0.0;
+        for corner in 0..8 {
+            let mut weight = 1.0;
+            let mut at = [0; 3];
+            for axis in 0..3 {
+                let upper = corner >> axis & 1 == 1;
+                at[axis] = lo[axis] + upper as usize;
+                weight *= if upper { frac[axis] } else { 1.0 - frac[axis] };
+            }
+            let index = self.get_index(at[0], at[1], at[2]);
+            if weight > 0.0 && !self.solid[index] {
+                sum += weight * field[index];
+                total += weight;
+            }
+        }
+        if total > 0.0 {
+            sum / total
+        } else {
+            0.0
+        }
     }
 }
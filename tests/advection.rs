@@ -0,0 +1,140 @@
+extern crate fluid_gdc03;
+
+use fluid_gdc03::*;
+
+/// Carries a cube of density 1 through a uniform periodic flow and returns
+/// the largest and smallest density left afterwards.
+fn advect_blob(scheme: AdvectionScheme, interpolation: Interpolation) -> (f32, f32) {
+    let mut grid = WindGrid::new(32, 8, 8);
+    for kk in 3..=6 {
+        for jj in 3..=6 {
+            for ii in 4..=7 {
+                let index = grid.get_index(ii, jj, kk);
+                grid.density[index] = 1.0;
+            }
+        }
+    }
+    // 0.3 cells per step, so the blob keeps falling between cell centers
+    let flow = grid.transform.velocity_to_grid(grid.size, Vel { x: 0.3, y: 0.0, z: 0.0 });
+    for v in grid.x_vel.iter_mut() {
+        *v = flow.x;
+    }
+
+    let config = SolverConfig {
+        dt: 1.0,
+        diffusion: 0.0,
+        viscosity: 0.0,
+        boundary: BoundaryConditions::uniform(Boundary::Periodic),
+        density_advection: scheme,
+        interpolation,
+        ..SolverConfig::default()
+    };
+    for _ in 0..30 {
+        step_fluid(&mut grid, &config);
+    }
+    let interior = (1..=8).flat_map(|kk| (1..=8).flat_map(move |jj| (1..=32).map(move |ii| (ii, jj, kk))));
+    interior.fold((f32::NEG_INFINITY, f32::INFINITY), |(max, min), (ii, jj, kk)| {
+        let d = grid.density[grid.get_index(ii, jj, kk)];
+        (max.max(d), min.min(d))
+    })
+}
+
+#[test]
+fn higher_order_advection_keeps_a_sharp_blob() {
+    let (semi_lagrangian, _) = advect_blob(AdvectionScheme::SemiLagrangian, Interpolation::Linear);
+    for &scheme in [AdvectionScheme::MacCormack, AdvectionScheme::Bfecc].iter() {
+        let (peak, low) = advect_blob(scheme, Interpolation::Linear);
+        assert!(
+            peak > semi_lagrangian + 0.1,
+            "{:?} kept a peak of {} against {} for semi-Lagrangian",
+            scheme,
+            peak,
+            semi_lagrangian
+        );
+        // the limiter keeps the correction from overshooting
+        assert!(peak <= 1.0 + 1e-5 && low >= -1e-5, "{:?} went out of range: {}..{}", scheme, low, peak);
+    }
+}
+
+#[test]
+fn monotone_cubic_lookups_keep_a_sharp_blob() {
+    let (linear, _) = advect_blob(AdvectionScheme::SemiLagrangian, Interpolation::Linear);
+    let (peak, low) = advect_blob(AdvectionScheme::SemiLagrangian, Interpolation::MonotoneCubic);
+    assert!(peak > linear + 0.1, "cubic kept a peak of {} against {} for linear", peak, linear);
+    assert!(peak <= 1.0 + 1e-5 && low >= -1e-5, "cubic went out of range: {}..{}", low, peak);
+}
+
+/// Turns a disc of density once around the middle of the grid with a
+/// solid-body rotation and returns how far, in cells, its centroid ends up
+/// from where the exact rotation would carry it.
+fn rotate_disc(backtrace: Backtrace) -> f32 {
+    let n = 48;
+    let mut grid = WindGrid::new(n, n, 4);
+    let middle = (n as f32 + 1.0) / 2.0;
+    let (radius, start) = (3.0, 14.0);
+    for kk in 1..=4 {
+        for jj in 1..=n {
+            for ii in 1..=n {
+                let (x, y) = (ii as f32 - middle - start, jj as f32 - middle);
+                if x * x + y * y < radius * radius {
+                    let index = grid.get_index(ii, jj, kk);
+                    grid.density[index] = 1.0;
+                }
+            }
+        }
+    }
+
+    // radians per step, with one meter cells and one second steps
+    let omega = 0.05;
+    let steps = 31;
+    let config = SolverConfig {
+        dt: 1.0,
+        diffusion: 0.0,
+        viscosity: 0.0,
+        backtrace,
+        ..SolverConfig::default()
+    };
+    for _ in 0..steps {
+        // the projection and the walls bend the flow a little, so it is
+        // imposed again before every step
+        for kk in 0..=5 {
+            for jj in 0..=n + 1 {
+                for ii in 0..=n + 1 {
+                    let (x, y) = (ii as f32 - middle, jj as f32 - middle);
+                    let v = Vel { x: -omega * y, y: omega * x, z: 0.0 };
+                    let v = grid.transform.velocity_to_grid(grid.size, v);
+                    let index = grid.get_index(ii, jj, kk);
+                    grid.x_vel[index] = v.x;
+                    grid.y_vel[index] = v.y;
+                    grid.z_vel[index] = v.z;
+                }
+            }
+        }
+        step_fluid(&mut grid, &config);
+    }
+
+    let angle = omega * steps as f32;
+    let exact = (start * angle.cos(), start * angle.sin());
+    let (mut total, mut cx, mut cy) = (0.0, 0.0, 0.0);
+    for kk in 1..=4 {
+        for jj in 1..=n {
+            for ii in 1..=n {
+                let d = grid.density[grid.get_index(ii, jj, kk)];
+                total += d;
+                cx += d * (ii as f32 - middle);
+                cy += d * (jj as f32 - middle);
+            }
+        }
+    }
+    (cx / total - exact.0).hypot(cy / total - exact.1)
+}
+
+#[test]
+fn runge_kutta_backtraces_follow_a_rotation_more_closely() {
+    let euler = rotate_disc(Backtrace::Euler);
+    let (rk2, rk3) = (rotate_disc(Backtrace::Rk2), rotate_disc(Backtrace::Rk3));
+    // Euler steps along the tangent, so the disc spirals outwards
+    assert!(euler > 0.3, "Euler drifted only {} cells", euler);
+    assert!(rk2 < 0.1 * euler, "RK2 drifted {} cells against {} for Euler", rk2, euler);
+    assert!(rk3 <= rk2, "RK3 drifted {} cells against {} for RK2", rk3, rk2);
+}
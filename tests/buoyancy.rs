@@ -0,0 +1,35 @@
+extern crate fluid_gdc03;
+
+use fluid_gdc03::*;
+
+/// World velocity in the middle of a uniformly warm grid after one step.
+/// With open faces the projection leaves the uniform lift alone.
+fn lift_after_one_step(rotation: [f32; 4], up: [f32; 3]) -> Vel {
+    let mut grid = WindGrid::new(8, 8, 8);
+    grid.transform = GridTransform::new([0.0; 3], 0.25, rotation);
+    for t in grid.temperature.iter_mut() {
+        *t = 1.0;
+    }
+    let config = SolverConfig {
+        dt: 0.1,
+        boundary: BoundaryConditions::uniform(Boundary::Outflow),
+        buoyancy: Buoyancy { lift: 2.0, up, ..Buoyancy::default() },
+        ..SolverConfig::default()
+    };
+    step_fluid(&mut grid, &config);
+    grid.get_velocity(Pos { x: 4, y: 4, z: 4 })
+}
+
+fn assert_close(v: Vel, expected: [f32; 3]) {
+    let error = [v.x - expected[0], v.y - expected[1], v.z - expected[2]];
+    assert!(error.iter().all(|e| e.abs() < 1e-4), "{:?} instead of {:?}", v, expected);
+}
+
+#[test]
+fn buoyancy_lifts_along_world_up_on_a_rotated_grid() {
+    let half = std::f32::consts::FRAC_1_SQRT_2;
+    // grid +x points along world +y
+    let rotation = [0.0, 0.0, half, half];
+    assert_close(lift_after_one_step(rotation, [0.0, 1.0, 0.0]), [0.0, 0.2, 0.0]);
+    assert_close(lift_after_one_step([0.0, 0.0, 0.0, 1.0], [0.0, 0.0, -3.0]), [0.0, 0.0, -0.2]);
+}
@@ -0,0 +1,61 @@
+extern crate fluid_gdc03;
+
+use fluid_gdc03::*;
+
+/// A periodic grid of one meter cells where everything moves at 3.5 m/s
+/// along x.
+fn uniform_flow() -> WindGrid {
+    let mut grid = WindGrid::new(16, 8, 8);
+    let flow = grid.transform.velocity_to_grid(grid.size, Vel { x: 3.5, y: 0.0, z: 0.0 });
+    for v in grid.x_vel.iter_mut() {
+        *v = flow.x;
+    }
+    grid
+}
+
+fn frame_config(max_substeps: usize) -> SolverConfig {
+    SolverConfig {
+        dt: 1.0,
+        diffusion: 0.0,
+        viscosity: 0.0,
+        max_cfl: 1.0,
+        max_substeps,
+        boundary: BoundaryConditions::uniform(Boundary::Periodic),
+        ..SolverConfig::default()
+    }
+}
+
+#[test]
+fn fast_flow_is_split_into_substeps_under_the_cfl_limit() {
+    let mut grid = uniform_flow();
+    let config = frame_config(16);
+    assert!((grid.cfl_number(1.0) - 3.5).abs() < 1e-4);
+    let stats = step_frame(&mut grid, &config, 1.0);
+    // 3.5 cells per frame need 4 steps of 0.875 cells
+    assert_eq!(stats.substeps, 4);
+    assert!(stats.cfl <= config.max_cfl, "CFL number {}", stats.cfl);
+    assert!((stats.cfl - 0.875).abs() < 1e-4, "CFL number {}", stats.cfl);
+}
+
+#[test]
+fn substeps_stop_at_the_limit() {
+    let mut grid = uniform_flow();
+    let stats = step_frame(&mut grid, &frame_config(2), 1.0);
+    assert_eq!(stats.substeps, 2);
+    assert!(stats.cfl > 1.0);
+
+    // an unvalidated zero still takes the frame in one step
+    let mut grid = uniform_flow();
+    let stats = step_frame(&mut grid, &frame_config(0), 1.0);
+    assert_eq!(stats.substeps, 1);
+}
+
+#[test]
+fn still_air_takes_a_whole_number_of_steps_exactly() {
+    for &(frame_time, dt, steps) in [(0.1, 0.01, 10), (0.3, 0.1, 3), (1.0, 0.2, 5), (0.05, 0.01, 5)].iter() {
+        let mut grid = WindGrid::new(8, 8, 8);
+        let config = SolverConfig { dt, ..frame_config(16) };
+        let stats = step_frame(&mut grid, &config, frame_time);
+        assert_eq!(stats.substeps, steps, "a frame of {} s in steps of {} s", frame_time, dt);
+    }
+}
@@ -0,0 +1,72 @@
+extern crate fluid_gdc03;
+
+use fluid_gdc03::*;
+
+#[test]
+fn stale_handles_do_not_reach_the_emitter_reusing_their_slot() {
+    let mut grid = WindGrid::new(8, 8, 8);
+    let sphere = EmitterShape::Sphere { radius: 1.0 };
+    let first = grid.add_emitter(Emitter::new([2.0, 2.0, 2.0], sphere));
+    let second = grid.add_emitter(Emitter::new([6.0, 6.0, 6.0], sphere));
+    assert!(grid.remove_emitter(first).is_some());
+
+    let reused = grid.add_emitter(Emitter { density: 3.0, ..Emitter::new([4.0, 4.0, 4.0], sphere) });
+    assert!(reused != first);
+    assert!(grid.emitter_mut(first).is_none());
+    assert!(grid.remove_emitter(first).is_none());
+    assert_eq!(grid.emitter_mut(reused).map(|e| e.density), Some(3.0));
+    assert_eq!(grid.emitter_mut(second).map(|e| e.position), Some([6.0, 6.0, 6.0]));
+
+    assert_eq!(grid.remove_emitter(reused).map(|e| e.position), Some([4.0, 4.0, 4.0]));
+    assert!(grid.emitter_mut(reused).is_none());
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+#[test]
+fn cones_only_emit_along_their_direction() {
+    let (length, radius) = (8.0, 3.0);
+    for &direction in [[1.0, 0.0, 0.0], [0.0, 0.0, -2.0]].iter() {
+        let mut grid = WindGrid::new(16, 16, 16);
+        let tip = grid.transform.cell_to_world([8.0, 8.0, 8.0]);
+        let shape = EmitterShape::Cone { direction, length, radius };
+        grid.add_emitter(Emitter { density: 1.0, ..Emitter::new(tip, shape) });
+        let config = SolverConfig { dt: 0.1, diffusion: 0.0, ..SolverConfig::default() };
+        step_fluid(&mut grid, &config);
+
+        let l = dot(direction, direction).sqrt();
+        let d = [direction[0] / l, direction[1] / l, direction[2] / l];
+        let density_at = |along: f32| {
+            let ii = (8.0 + d[0] * along) as usize;
+            let jj = (8.0 + d[1] * along) as usize;
+            let kk = (8.0 + d[2] * along) as usize;
+            grid.density[grid.get_index(ii, jj, kk)]
+        };
+        assert!(density_at(4.0) > 0.0, "{:?}: nothing along the axis", direction);
+        assert_eq!(density_at(-4.0), 0.0, "{:?}: density behind the tip", direction);
+
+        for kk in 1..=16 {
+            for jj in 1..=16 {
+                for ii in 1..=16 {
+                    if grid.density[grid.get_index(ii, jj, kk)] == 0.0 {
+                        continue;
+                    }
+                    let v = [ii as f32 - 8.0, jj as f32 - 8.0, kk as f32 - 8.0];
+                    let along = dot(v, d);
+                    let across = [v[0] - d[0] * along, v[1] - d[1] * along, v[2] - d[2] * along];
+                    let across = dot(across, across).sqrt();
+                    assert!(
+                        along >= 0.0 && along <= length && across <= radius * along / length + 1e-4,
+                        "{:?}: density at {} {} {}",
+                        direction,
+                        ii,
+                        jj,
+                        kk
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,59 @@
+extern crate fluid_gdc03;
+
+use fluid_gdc03::*;
+
+#[test]
+fn density_does_not_enter_or_pass_a_solid_wall() {
+    // A wall across the whole channel, with smoke blown straight at it
+    let (nx, ny, nz) = (24, 10, 10);
+    let (wall_min, wall_max) = (10, 12);
+    for &layout in [VelocityLayout::Collocated, VelocityLayout::Staggered].iter() {
+        let mut grid = WindGrid::new(nx, ny, nz);
+        grid.set_velocity_layout(layout);
+        grid.add_solid_box(Pos { x: wall_min, y: 1, z: 1 }, Pos { x: wall_max, y: ny, z: nz });
+        for kk in 1..=nz {
+            for jj in 1..=ny {
+                for ii in 2..=4 {
+                    let pos = Pos { x: ii, y: jj, z: kk };
+                    grid.add_density_source(pos, 10.0);
+                    grid.add_velocity_source(pos, Vel { x: 20.0, y: 0.0, z: 0.0 });
+                }
+            }
+        }
+        let config = SolverConfig { dt: 0.05, ..SolverConfig::default() };
+        for _ in 0..40 {
+            step_fluid(&mut grid, &config);
+        }
+
+        let mut in_front = 0.0;
+        for kk in 1..=nz {
+            for jj in 1..=ny {
+                for ii in 1..=nx {
+                    let density = grid.density[grid.get_index(ii, jj, kk)];
+                    if ii < wall_min {
+                        in_front += density;
+                    } else {
+                        assert!(
+                            density == 0.0,
+                            "{:?}: density {} at {} {} {}",
+                            layout,
+                            density,
+                            ii,
+                            jj,
+                            kk
+                        );
+                    }
+                }
+            }
+        }
+        // the smoke did reach the wall
+        let touching = grid.density[grid.get_index(wall_min - 1, ny / 2, nz / 2)];
+        assert!(
+            in_front > 0.0 && touching > 0.1,
+            "{:?}: {} in front of the wall, {} next to it",
+            layout,
+            in_front,
+            touching
+        );
+    }
+}
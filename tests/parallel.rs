@@ -0,0 +1,70 @@
+#![cfg(feature = "parallel")]
+
+extern crate fluid_gdc03;
+extern crate rand;
+extern crate rayon;
+
+use fluid_gdc03::*;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+
+/// A grid with random velocities in -1..1 m/s and random density.
+fn random_grid(nx: usize, ny: usize, nz: usize) -> WindGrid {
+    let mut grid = WindGrid::new(nx, ny, nz);
+    let mut rng = SmallRng::seed_from_u64(11);
+    for kk in 1..=nz {
+        for jj in 1..=ny {
+            for ii in 1..=nx {
+                let v = Vel {
+                    x: rng.gen_range(-1.0..1.0),
+                    y: rng.gen_range(-1.0..1.0),
+                    z: rng.gen_range(-1.0..1.0),
+                };
+                let v = grid.transform.velocity_to_grid(grid.size, v);
+                let index = grid.get_index(ii, jj, kk);
+                grid.x_vel[index] = v.x;
+                grid.y_vel[index] = v.y;
+                grid.z_vel[index] = v.z;
+                grid.density[index] = rng.gen_range(0.0..1.0);
+            }
+        }
+    }
+    grid
+}
+
+/// Steps a random grid a few times on a pool of `threads` threads.
+fn run_on(threads: usize, config: &SolverConfig) -> WindGrid {
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+    pool.install(|| {
+        let mut grid = random_grid(24, 20, 17);
+        for _ in 0..3 {
+            step_fluid(&mut grid, config);
+        }
+        grid
+    })
+}
+
+#[test]
+fn red_black_sweeps_do_not_depend_on_the_thread_count() {
+    let config = SolverConfig {
+        dt: 0.05,
+        diffusion: 1e-3,
+        viscosity: 1e-3,
+        iterations: 20,
+        sweep_order: SweepOrder::RedBlack,
+        diffusion_solver: LinearSolver::GaussSeidel,
+        pressure_solver: LinearSolver::GaussSeidel,
+        ..SolverConfig::default()
+    };
+    let serial = run_on(1, &config);
+    let parallel = run_on(4, &config);
+    let fields = [
+        ("x_vel", &serial.x_vel, &parallel.x_vel),
+        ("y_vel", &serial.y_vel, &parallel.y_vel),
+        ("z_vel", &serial.z_vel, &parallel.z_vel),
+        ("density", &serial.density, &parallel.density),
+    ];
+    for &(name, serial, parallel) in fields.iter() {
+        assert!(serial == parallel, "{} differs between 1 and 4 threads", name);
+    }
+}
@@ -0,0 +1,288 @@
+extern crate fluid_gdc03;
+extern crate rand;
+
+use fluid_gdc03::*;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+
+/// A grid whose interior velocity components are random in -1..1 m/s.
+fn random_flow(nx: usize, ny: usize, nz: usize, layout: VelocityLayout) -> WindGrid {
+    let mut grid = WindGrid::new(nx, ny, nz);
+    grid.set_velocity_layout(layout);
+    let mut rng = SmallRng::seed_from_u64(7);
+    for kk in 1..=nz {
+        for jj in 1..=ny {
+            for ii in 1..=nx {
+                let v = Vel {
+                    x: rng.gen_range(-1.0..1.0),
+                    y: rng.gen_range(-1.0..1.0),
+                    z: rng.gen_range(-1.0..1.0),
+                };
+                let v = grid.transform.velocity_to_grid(grid.size, v);
+                let index = grid.get_index(ii, jj, kk);
+                grid.x_vel[index] = v.x;
+                grid.y_vel[index] = v.y;
+                grid.z_vel[index] = v.z;
+            }
+        }
+    }
+    grid
+}
+
+/// Root mean square divergence in cells per second. Collocated velocities
+/// are differenced across two cells, and only away from the walls, where
+/// the differences stay clear of the ghost cells.
+fn divergence(grid: &WindGrid) -> f32 {
+    let size = grid.size;
+    let n = [size.x as f32, size.y as f32, size.z as f32];
+    let (row, slab) = (size.x + 2, (size.x + 2) * (size.y + 2));
+    let margin = match grid.velocity_layout {
+        VelocityLayout::Collocated => 1,
+        VelocityLayout::Staggered => 0,
+    };
+    let mut sum = 0.0;
+    let mut count = 0;
+    for kk in 1 + margin..=size.z - margin {
+        for jj in 1 + margin..=size.y - margin {
+            for ii in 1 + margin..=size.x - margin {
+                let ix = grid.get_index(ii, jj, kk);
+                let div = match grid.velocity_layout {
+                    VelocityLayout::Collocated => {
+                        0.5 * ((grid.x_vel[ix + 1] - grid.x_vel[ix - 1]) * n[0]
+                            + (grid.y_vel[ix + row] - grid.y_vel[ix - row]) * n[1]
+                            + (grid.z_vel[ix + slab] - grid.z_vel[ix - slab]) * n[2])
+                    }
+                    VelocityLayout::Staggered => {
+                        (grid.x_vel[ix] - grid.x_vel[ix - 1]) * n[0]
+                            + (grid.y_vel[ix] - grid.y_vel[ix - row]) * n[1]
+                            + (grid.z_vel[ix] - grid.z_vel[ix - slab]) * n[2]
+                    }
+                };
+                sum += div * div;
+                count += 1;
+            }
+        }
+    }
+    (sum / count as f32).sqrt()
+}
+
+/// Root mean square speed of the interior cells in m/s.
+fn rms_speed(grid: &WindGrid) -> f32 {
+    let size = grid.size;
+    let mut sum = 0.0;
+    for kk in 1..=size.z {
+        for jj in 1..=size.y {
+            for ii in 1..=size.x {
+                let v = grid.get_velocity(Pos { x: ii, y: jj, z: kk });
+                sum += v.x * v.x + v.y * v.y + v.z * v.z;
+            }
+        }
+    }
+    (sum / (size.x * size.y * size.z) as f32).sqrt()
+}
+
+/// A step too short to move anything, so that it only projects, with the
+/// pressure solve run to convergence.
+fn projection_config(pressure_solver: LinearSolver) -> SolverConfig {
+    SolverConfig {
+        dt: 1e-6,
+        diffusion: 0.0,
+        viscosity: 0.0,
+        iterations: 1000,
+        tolerance: 1e-5,
+        pressure_solver,
+        ..SolverConfig::default()
+    }
+}
+
+#[test]
+fn projection_reduces_divergence_on_non_cubic_grids() {
+    let solver = LinearSolver::ConjugateGradient(Preconditioner::IncompleteCholesky);
+    for &(nx, ny, nz) in [(32, 32, 4), (64, 16, 8)].iter() {
+        let mut grid = random_flow(nx, ny, nz, VelocityLayout::Collocated);
+        let (div_before, speed_before) = (divergence(&grid), rms_speed(&grid));
+        step_fluid(&mut grid, &projection_config(solver));
+        let (div_after, speed_after) = (divergence(&grid), rms_speed(&grid));
+        assert!(
+            div_after < 0.6 * div_before,
+            "{}x{}x{}: divergence went from {} to {}",
+            nx,
+            ny,
+            nz,
+            div_before,
+            div_after
+        );
+        // removing the divergent part can only take energy out
+        assert!(
+            speed_after <= speed_before,
+            "{}x{}x{}: RMS speed went from {} to {}",
+            nx,
+            ny,
+            nz,
+            speed_before,
+            speed_after
+        );
+    }
+}
+
+#[test]
+fn staggered_projection_removes_divergence_on_non_cubic_grids() {
+    let solver = LinearSolver::ConjugateGradient(Preconditioner::IncompleteCholesky);
+    for &(nx, ny, nz) in [(32, 32, 4), (64, 16, 8)].iter() {
+        let mut grid = random_flow(nx, ny, nz, VelocityLayout::Staggered);
+        let before = divergence(&grid);
+        step_fluid(&mut grid, &projection_config(solver));
+        let after = divergence(&grid);
+        assert!(
+            after < 1e-4 * before,
+            "{}x{}x{}: face divergence went from {} to {}",
+            nx,
+            ny,
+            nz,
+            before,
+            after
+        );
+    }
+}
+
+#[test]
+fn conjugate_gradient_projection_removes_divergence() {
+    // Only the staggered layout can be made exactly divergence free, see
+    // `VelocityLayout::Collocated`
+    for &preconditioner in [Preconditioner::Jacobi, Preconditioner::IncompleteCholesky].iter() {
+        let mut grid = random_flow(16, 16, 16, VelocityLayout::Staggered);
+        let before = divergence(&grid);
+        step_fluid(&mut grid, &projection_config(LinearSolver::ConjugateGradient(preconditioner)));
+        let after = divergence(&grid);
+        assert!(
+            after < 1e-4 * before,
+            "{:?}: divergence went from {} to {}",
+            preconditioner,
+            before,
+            after
+        );
+    }
+}
+
+#[test]
+fn projection_couples_periodic_faces() {
+    let solvers = [
+        LinearSolver::GaussSeidel,
+        LinearSolver::ConjugateGradient(Preconditioner::IncompleteCholesky),
+        LinearSolver::Multigrid,
+    ];
+    for &pressure_solver in solvers.iter() {
+        let mut grid = random_flow(16, 16, 16, VelocityLayout::Staggered);
+        let config = SolverConfig {
+            boundary: BoundaryConditions::uniform(Boundary::Periodic),
+            ..projection_config(pressure_solver)
+        };
+        let before = divergence(&grid);
+        step_fluid(&mut grid, &config);
+        let after = divergence(&grid);
+        assert!(
+            after < 1e-4 * before,
+            "{:?}: face divergence went from {} to {}",
+            pressure_solver,
+            before,
+            after
+        );
+    }
+}
+
+#[test]
+fn closed_box_does_not_fall_under_gravity() {
+    // The pressure has zero gradient across the walls in both layouts, so
+    // it balances a uniform force instead of letting the fluid fall through
+    // the floor. The collocated layout still rings next to the walls, so
+    // only the net flow is checked.
+    for &layout in [VelocityLayout::Collocated, VelocityLayout::Staggered].iter() {
+        let mut grid = WindGrid::new(16, 16, 16);
+        grid.set_velocity_layout(layout);
+        let config = SolverConfig {
+            dt: 0.1,
+            uniform_force: Vel { x: 0.0, y: -9.8, z: 0.0 },
+            iterations: 200,
+            tolerance: 1e-6,
+            pressure_solver: LinearSolver::ConjugateGradient(Preconditioner::IncompleteCholesky),
+            boundary: BoundaryConditions::uniform(Boundary::FreeSlip),
+            ..SolverConfig::default()
+        };
+        for _ in 0..5 {
+            step_fluid(&mut grid, &config);
+        }
+        let size = grid.size;
+        let mut sum = 0.0;
+        for kk in 1..=size.z {
+            for jj in 1..=size.y {
+                for ii in 1..=size.x {
+                    sum += grid.get_velocity(Pos { x: ii, y: jj, z: kk }).y;
+                }
+            }
+        }
+        let mean = sum / (size.x * size.y * size.z) as f32;
+        // 4.9 m/s without the pressure
+        assert!(mean.abs() < 0.1, "{:?}: mean vertical velocity {}", layout, mean);
+    }
+}
+
+#[test]
+fn multigrid_projects_flat_and_small_closed_boxes() {
+    // Too flat or too small to coarsen in every direction, and singular
+    // with the walls closed all around
+    for &(nx, ny, nz) in [(32, 32, 1), (4, 4, 4)].iter() {
+        let mut grid = random_flow(nx, ny, nz, VelocityLayout::Staggered);
+        let config = SolverConfig {
+            iterations: 100,
+            tolerance: 0.0,
+            boundary: BoundaryConditions::uniform(Boundary::FreeSlip),
+            ..projection_config(LinearSolver::Multigrid)
+        };
+        let (div_before, speed_before) = (divergence(&grid), rms_speed(&grid));
+        for _ in 0..3 {
+            step_fluid(&mut grid, &config);
+        }
+        let (div_after, speed_after) = (divergence(&grid), rms_speed(&grid));
+        assert!(
+            div_after < 1e-4 * div_before,
+            "{}x{}x{}: face divergence went from {} to {}",
+            nx,
+            ny,
+            nz,
+            div_before,
+            div_after
+        );
+        // the divergence free part of the flow survives
+        assert!(
+            speed_after > 0.3 * speed_before,
+            "{}x{}x{}: RMS speed went from {} to {}",
+            nx,
+            ny,
+            nz,
+            speed_before,
+            speed_after
+        );
+    }
+}
+
+#[test]
+fn multigrid_converges_with_closed_walls() {
+    // Flat enough that the walls dominate the pressure
+    let mut grid = random_flow(96, 96, 4, VelocityLayout::Staggered);
+    let config = SolverConfig {
+        iterations: 10,
+        tolerance: 0.0,
+        boundary: BoundaryConditions::uniform(Boundary::FreeSlip),
+        ..projection_config(LinearSolver::Multigrid)
+    };
+    let before = divergence(&grid);
+    let stats = step_fluid(&mut grid, &config);
+    let after = divergence(&grid);
+    assert!(
+        after < 1e-4 * before,
+        "face divergence went from {} to {} after {:?}",
+        before,
+        after,
+        stats.pressure
+    );
+}
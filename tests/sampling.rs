@@ -0,0 +1,82 @@
+extern crate fluid_gdc03;
+
+use fluid_gdc03::*;
+
+/// Linear velocity in m/s at the world position `p`.
+fn velocity(p: [f32; 3]) -> Vel {
+    Vel {
+        x: 1.0 + 0.5 * p[0],
+        y: 2.0 - 0.25 * p[1] + 0.1 * p[2],
+        z: -1.0 + 0.3 * p[0] + 0.2 * p[2],
+    }
+}
+
+fn density(p: [f32; 3]) -> f32 {
+    2.0 + 0.1 * p[0] + 0.2 * p[1] + 0.3 * p[2]
+}
+
+/// A non-cubic, rotated grid holding the linear fields in every cell, ghost
+/// cells included, so that interpolating them is exact.
+fn linear_grid() -> WindGrid {
+    let mut grid = WindGrid::new(8, 6, 5);
+    // a quarter turn about y, so that the grid axes differ from the world axes
+    let quarter = 0.5f32.sqrt();
+    grid.transform = GridTransform::new([1.0, 2.0, 3.0], 0.5, [0.0, quarter, 0.0, quarter]);
+    for kk in 0..=6 {
+        for jj in 0..=7 {
+            for ii in 0..=9 {
+                let p = grid.transform.cell_to_world([ii as f32, jj as f32, kk as f32]);
+                let v = grid.transform.velocity_to_grid(grid.size, velocity(p));
+                let index = grid.get_index(ii, jj, kk);
+                grid.x_vel[index] = v.x;
+                grid.y_vel[index] = v.y;
+                grid.z_vel[index] = v.z;
+                grid.density[index] = density(p);
+            }
+        }
+    }
+    grid
+}
+
+fn assert_close(actual: Vel, expected: Vel, what: &str) {
+    let error = (actual.x - expected.x)
+        .abs()
+        .max((actual.y - expected.y).abs())
+        .max((actual.z - expected.z).abs());
+    assert!(error < 1e-4, "{}: {:?} instead of {:?}", what, actual, expected);
+}
+
+#[test]
+fn get_velocity_returns_each_component_of_the_cell() {
+    let grid = linear_grid();
+    for &(ii, jj, kk) in [(1, 1, 1), (3, 5, 2), (8, 6, 5)].iter() {
+        let p = grid.transform.cell_to_world([ii as f32, jj as f32, kk as f32]);
+        let actual = grid.get_velocity(Pos { x: ii, y: jj, z: kk });
+        assert_close(actual, velocity(p), &format!("cell {} {} {}", ii, jj, kk));
+    }
+}
+
+#[test]
+fn sampling_interpolates_linear_fields_exactly() {
+    for &layout in [VelocityLayout::Collocated, VelocityLayout::Staggered].iter() {
+        let mut grid = linear_grid();
+        grid.set_velocity_layout(layout);
+        for &c in [[1.0, 1.0, 1.0], [2.3, 4.75, 1.5], [7.9, 1.2, 4.6], [4.5, 3.5, 2.5]].iter() {
+            let p = grid.transform.cell_to_world(c);
+            assert_close(grid.sample_velocity(p), velocity(p), &format!("{:?} at cell {:?}", layout, c));
+            let sampled = grid.sample_density(p);
+            assert!((sampled - density(p)).abs() < 1e-4, "density {} at cell {:?}", sampled, c);
+        }
+    }
+}
+
+#[test]
+fn sampling_outside_the_grid_clamps_to_its_boundary() {
+    let grid = linear_grid();
+    // beyond the x and z faces, which are half a cell outside the last centers
+    let outside = grid.transform.cell_to_world([12.0, 2.25, -3.0]);
+    let boundary = grid.transform.cell_to_world([8.5, 2.25, 0.5]);
+    assert_close(grid.sample_velocity(outside), velocity(boundary), "outside");
+    let sampled = grid.sample_density(outside);
+    assert!((sampled - density(boundary)).abs() < 1e-4, "density {} outside", sampled);
+}
@@ -0,0 +1,79 @@
+extern crate fluid_gdc03;
+
+use fluid_gdc03::*;
+
+/// A non-cubic grid turned a quarter about z, with a different density in
+/// every cell.
+fn labelled_grid() -> WindGrid {
+    let mut grid = WindGrid::new(8, 6, 4);
+    let quarter = 0.5f32.sqrt();
+    grid.transform = GridTransform::new([1.0, 2.0, 3.0], 0.5, [0.0, 0.0, quarter, quarter]);
+    grid.ambient = Ambient {
+        density: 0.25,
+        temperature: 3.0,
+        velocity: Vel { x: 1.0, y: 2.0, z: 3.0 },
+    };
+    for kk in 1..=4 {
+        for jj in 1..=6 {
+            for ii in 1..=8 {
+                let index = grid.get_index(ii, jj, kk);
+                grid.density[index] = (ii + 10 * jj + 100 * kk) as f32;
+            }
+        }
+    }
+    grid
+}
+
+#[test]
+fn scrolling_shifts_the_contents_and_fills_from_the_ambient_state() {
+    let before = labelled_grid();
+    let mut grid = before.clone();
+    let offset = [2, -1, 1];
+    grid.scroll(offset);
+
+    for kk in 1..=4 {
+        for jj in 1..=6 {
+            for ii in 1..=8 {
+                let index = grid.get_index(ii, jj, kk);
+                let from = [ii as isize + offset[0], jj as isize + offset[1], kk as isize + offset[2]];
+                let inside = (0..3).all(|axis| from[axis] >= 1 && from[axis] <= [8, 6, 4][axis]);
+                if inside {
+                    let old = before.get_index(from[0] as usize, from[1] as usize, from[2] as usize);
+                    assert_eq!(grid.density[index], before.density[old], "cell {} {} {}", ii, jj, kk);
+                } else {
+                    assert_eq!(grid.density[index], 0.25, "cell {} {} {}", ii, jj, kk);
+                    assert_eq!(grid.temperature[index], 3.0, "cell {} {} {}", ii, jj, kk);
+                    let v = grid.get_velocity(Pos { x: ii, y: jj, z: kk });
+                    let error = (v.x - 1.0).abs().max((v.y - 2.0).abs()).max((v.z - 3.0).abs());
+                    assert!(error < 1e-5, "cell {} {} {}: {:?}", ii, jj, kk, v);
+                }
+            }
+        }
+    }
+
+    // The grid x axis points along world y and its y axis along world -x,
+    // so the (1, -0.5, 0.5) meters along the grid axes are (0.5, 1, 0.5)
+    let origin = grid.transform.origin;
+    let expected = [1.5, 3.0, 3.5];
+    for axis in 0..3 {
+        assert!((origin[axis] - expected[axis]).abs() < 1e-5, "origin {:?}", origin);
+    }
+
+    // and what was at a point in the world is still there
+    let point = before.transform.cell_to_world([5.0, 3.0, 2.0]);
+    assert_eq!(grid.cell_at(point), Some(Pos { x: 3, y: 4, z: 1 }));
+    let density = grid.sample_density(point);
+    assert!((density - 235.0).abs() < 1e-3, "density {} at the point", density);
+}
+
+#[test]
+fn following_a_point_brings_it_to_the_central_cell() {
+    let mut grid = labelled_grid();
+    let point = grid.transform.cell_to_world([7.2, 2.4, 3.2]);
+    grid.follow(point);
+    let c = grid.transform.world_to_cell(point);
+    let expected = [4.2, 3.4, 2.2];
+    for axis in 0..3 {
+        assert!((c[axis] - expected[axis]).abs() < 1e-4, "{:?} is now at cell {:?}", point, c);
+    }
+}
@@ -0,0 +1,56 @@
+extern crate fluid_gdc03;
+
+use fluid_gdc03::*;
+
+#[test]
+fn velocity_sources_and_forces_are_cleared_separately() {
+    let mut grid = WindGrid::new(8, 8, 8);
+    let pos = Pos { x: 4, y: 4, z: 4 };
+    grid.add_force(pos, Vel { x: 1.0, y: 0.0, z: 0.0 });
+    grid.add_velocity_source(pos, Vel { x: 0.0, y: 2.0, z: 0.0 });
+
+    grid.clear_sources();
+    assert!(grid.velocity_source.is_none());
+    assert!(grid.force.is_some(), "clear_sources removed the forces");
+
+    grid.add_velocity_source(pos, Vel { x: 0.0, y: 2.0, z: 0.0 });
+    grid.clear_forces();
+    assert!(grid.force.is_none());
+    assert!(grid.velocity_source.is_some(), "clear_forces removed the velocity sources");
+}
+
+#[test]
+fn world_space_sources_and_solids_land_in_the_cell_containing_them() {
+    let mut grid = WindGrid::new(8, 8, 8);
+    grid.transform = GridTransform::new([10.0, 0.0, -5.0], 0.5, [0.0, 0.0, 0.0, 1.0]);
+    let point = [11.6, 2.1, -3.0];
+    let cell = grid.cell_at(point).unwrap();
+    assert_eq!(cell, Pos { x: 3, y: 4, z: 4 });
+
+    grid.add_density_source_at(point, 1.0);
+    grid.add_force_at(point, Vel { x: 1.0, y: 0.0, z: 0.0 });
+    grid.set_solid_at([10.5, 0.5, -4.5], true);
+    // outside the grid
+    grid.add_density_source_at([0.0, 0.0, 0.0], 1.0);
+    grid.set_solid_at([0.0, 0.0, 0.0], true);
+
+    let index = grid.get_index(cell.x, cell.y, cell.z);
+    let source = grid.density_source.as_ref().unwrap();
+    assert_eq!(source[index], 1.0);
+    assert_eq!(source.iter().sum::<f32>(), 1.0);
+    assert!(grid.force.as_ref().unwrap().x[index] > 0.0);
+    assert!(grid.is_solid(Pos { x: 1, y: 1, z: 1 }));
+    assert_eq!(grid.solid.iter().filter(|&&s| s).count(), 1);
+
+    // cell centers 2..=4 along every axis; 1.2 cells along y still miss 1 and 5
+    grid.clear_solids();
+    grid.add_solid_box_at([11.5, 1.5, -3.5], [0.5, 0.6, 0.5]);
+    for kk in 1..=8 {
+        for jj in 1..=8 {
+            for ii in 1..=8 {
+                let inside = (2..=4).contains(&ii) && (2..=4).contains(&jj) && (2..=4).contains(&kk);
+                assert_eq!(grid.is_solid(Pos { x: ii, y: jj, z: kk }), inside, "{} {} {}", ii, jj, kk);
+            }
+        }
+    }
+}
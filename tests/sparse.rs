@@ -0,0 +1,147 @@
+extern crate fluid_gdc03;
+
+use fluid_gdc03::*;
+
+#[test]
+fn sparse_diffusion_matches_a_dense_grid() {
+    // One meter across, so a window has far fewer cells than the domain
+    let n = 64;
+    let h = 1.0 / n as f32;
+    let center = Pos { x: n / 2, y: n / 2, z: n / 2 };
+    let config = SolverConfig {
+        dt: 0.1,
+        diffusion: 0.001,
+        viscosity: 0.0,
+        iterations: 100,
+        tolerance: 1e-7,
+        diffusion_solver: LinearSolver::ConjugateGradient(Preconditioner::IncompleteCholesky),
+        ..SolverConfig::default()
+    };
+
+    let mut dense = WindGrid::new(n, n, n);
+    dense.transform.cell_size = h;
+    let index = dense.get_index(center.x, center.y, center.z);
+    dense.density[index] = 1.0;
+    step_fluid(&mut dense, &config);
+
+    let mut sparse = SparseGrid::new(n, n, n);
+    sparse.transform.cell_size = h;
+    sparse.add_density(center, 1.0);
+    sparse.step(&config).unwrap();
+
+    let left = dense.density[index];
+    assert!(left < 0.5, "only {} of the density spread out", 1.0 - left);
+    for kk in 1..=n {
+        for jj in 1..=n {
+            for ii in 1..=n {
+                let pos = Pos { x: ii, y: jj, z: kk };
+                let expected = dense.density[dense.get_index(ii, jj, kk)];
+                let actual = sparse.density(pos);
+                assert!(
+                    (actual - expected).abs() < 1e-4,
+                    "{:?}: sparse density {} instead of {}",
+                    pos,
+                    actual,
+                    expected
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn a_moving_blob_matches_a_dense_grid_on_a_bounded_number_of_tiles() {
+    let (nx, ny, nz) = (96, 32, 32);
+    let config = SolverConfig {
+        dt: 0.1,
+        diffusion: 0.0,
+        viscosity: 0.0,
+        iterations: 100,
+        tolerance: 1e-5,
+        pressure_solver: LinearSolver::ConjugateGradient(Preconditioner::IncompleteCholesky),
+        ..SolverConfig::default()
+    };
+
+    // A cube of smoke one tile across, pushed along x
+    let mut dense = WindGrid::new(nx, ny, nz);
+    let mut sparse = SparseGrid::new(nx, ny, nz);
+    let push = Vel { x: 5.0, y: 0.0, z: 0.0 };
+    for kk in 13..=20 {
+        for jj in 13..=20 {
+            for ii in 9..=16 {
+                let index = dense.get_index(ii, jj, kk);
+                dense.density[index] = 1.0;
+                dense.x_vel[index] = dense.transform.velocity_to_grid(dense.size, push).x;
+                let pos = Pos { x: ii, y: jj, z: kk };
+                sparse.add_density(pos, 1.0);
+                sparse.add_velocity(pos, push);
+            }
+        }
+    }
+
+    // Total density and its center along x, dense then sparse
+    let moments = |dense: &WindGrid, sparse: &SparseGrid| {
+        let mut sums = [0.0f32; 4];
+        for kk in 1..=nz {
+            for jj in 1..=ny {
+                for ii in 1..=nx {
+                    let d = dense.density[dense.get_index(ii, jj, kk)];
+                    let s = sparse.density(Pos { x: ii, y: jj, z: kk });
+                    sums[0] += d;
+                    sums[1] += d * ii as f32;
+                    sums[2] += s;
+                    sums[3] += s * ii as f32;
+                }
+            }
+        }
+        (sums[0], sums[1] / sums[0], sums[2], sums[3] / sums[2])
+    };
+
+    let (_, start, _, _) = moments(&dense, &sparse);
+    let all = (nx / TILE) * (ny / TILE) * (nz / TILE);
+    for step in 0..20 {
+        step_fluid(&mut dense, &config);
+        sparse.step(&config).unwrap();
+        // the air stirred far from the smoke doesn't keep its tiles
+        assert!(
+            sparse.active_tiles() <= all / 3,
+            "step {}: {} of {} tiles active",
+            step,
+            sparse.active_tiles(),
+            all
+        );
+    }
+
+    let (dense_total, dense_center, sparse_total, sparse_center) = moments(&dense, &sparse);
+    assert!(dense_center - start > 2.0, "the blob only moved from {} to {}", start, dense_center);
+    assert!(
+        (sparse_total - dense_total).abs() < 0.01 * dense_total,
+        "sparse total {} instead of {}",
+        sparse_total,
+        dense_total
+    );
+    assert!(
+        (sparse_center - dense_center).abs() < 0.1,
+        "sparse center at {} instead of {}",
+        sparse_center,
+        dense_center
+    );
+}
+
+#[test]
+fn forces_the_sparse_grid_cannot_apply_are_rejected() {
+    let mut sparse = SparseGrid::new(16, 16, 16);
+    sparse.add_density(Pos { x: 8, y: 8, z: 8 }, 1.0);
+    let wind = SolverConfig { uniform_force: Vel { x: 1.0, y: 0.0, z: 0.0 }, ..SolverConfig::default() };
+    assert_eq!(sparse.step(&wind).map_err(|e| e.field), Err("uniform_force"));
+    let warm = SolverConfig {
+        buoyancy: Buoyancy { lift: 1.0, ..Buoyancy::default() },
+        ..SolverConfig::default()
+    };
+    assert_eq!(sparse.step(&warm).map_err(|e| e.field), Err("buoyancy.lift"));
+    let heavy = SolverConfig {
+        buoyancy: Buoyancy { weight: 1.0, ..Buoyancy::default() },
+        ..SolverConfig::default()
+    };
+    assert!(sparse.step(&heavy).is_ok());
+}
@@ -0,0 +1,73 @@
+extern crate fluid_gdc03;
+
+use fluid_gdc03::*;
+
+/// A grid holding a vortex around the z axis through cell `(cx, cy)`, with
+/// a Gaussian profile 3 cells wide and the same velocities at every z.
+fn vortex(nx: usize, ny: usize, nz: usize, cx: usize, cy: usize) -> WindGrid {
+    let mut grid = WindGrid::new(nx, ny, nz);
+    for kk in 1..=nz {
+        for jj in 1..=ny {
+            for ii in 1..=nx {
+                let (dx, dy) = (ii as f32 - cx as f32, jj as f32 - cy as f32);
+                let falloff = (-(dx * dx + dy * dy) / 9.0).exp();
+                let v = Vel { x: -dy * falloff, y: dx * falloff, z: 0.0 };
+                let v = grid.transform.velocity_to_grid(grid.size, v);
+                let index = grid.get_index(ii, jj, kk);
+                grid.x_vel[index] = v.x;
+                grid.y_vel[index] = v.y;
+            }
+        }
+    }
+    grid
+}
+
+/// Velocity change in m/s that vorticity confinement adds to one step of
+/// `grid`, at the cells `radius` around `(cx, cy)` in the bottom layer. The
+/// grid wraps around along z, so every layer is the same.
+fn confinement(grid: &WindGrid, cx: usize, cy: usize, radius: usize) -> Vec<Vel> {
+    let step = |vorticity: f32| {
+        let mut grid = grid.clone();
+        let config = SolverConfig {
+            dt: 0.1,
+            vorticity,
+            boundary: BoundaryConditions {
+                z_min: Boundary::Periodic,
+                z_max: Boundary::Periodic,
+                ..BoundaryConditions::default()
+            },
+            ..SolverConfig::default()
+        };
+        step_fluid(&mut grid, &config);
+        grid
+    };
+    let (with, without) = (step(5.0), step(0.0));
+    let mut change = Vec::new();
+    for jj in cy - radius..=cy + radius {
+        for ii in cx - radius..=cx + radius {
+            let pos = Pos { x: ii, y: jj, z: 1 };
+            let (a, b) = (with.get_velocity(pos), without.get_velocity(pos));
+            change.push(Vel { x: a.x - b.x, y: a.y - b.y, z: a.z - b.z });
+        }
+    }
+    change
+}
+
+#[test]
+fn confinement_does_not_depend_on_the_grid_proportions() {
+    // The same vortex on a square grid and on one twice as long in y and
+    // twice as deep; the extra room is far from the vortex
+    let square = confinement(&vortex(24, 24, 4, 12, 12), 12, 12, 4);
+    let long = confinement(&vortex(24, 48, 8, 12, 24), 12, 24, 4);
+    let largest = square.iter().fold(0.0f32, |m, v| m.max(v.x.abs()).max(v.y.abs()));
+    assert!(largest > 1e-3, "confinement only changed the velocity by {}", largest);
+    for (a, b) in square.iter().zip(long.iter()) {
+        let difference = (a.x - b.x).abs().max((a.y - b.y).abs()).max((a.z - b.z).abs());
+        assert!(
+            difference < 0.05 * largest,
+            "{:?} on the square grid against {:?} on the long one",
+            a,
+            b
+        );
+    }
+}